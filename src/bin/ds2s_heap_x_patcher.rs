@@ -0,0 +1,489 @@
+//! Applies the heap_x patches to DarkSoulsII.exe on disk, for launchers that cannot inject DLLs.
+//!
+//! A backup of the original executable is kept as "DarkSoulsII.exe.bak", and the original bytes
+//! of every patched site are recorded in a journal next to the output, which `--restore` uses
+//! to reverse the patches.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use ds2s_heap_x::{
+    builds::{self, journal_path, Version},
+    config::{Config, ConfigError},
+    definitions::{GroupState, PatchOp, PatchSet},
+    layers::{self, BASE_CONFIG},
+    log::Log,
+    memory::{FileMemory, Memory, MemoryError},
+    overrides::ARG_PREFIX,
+    patches::{self, SiteTable},
+    pe::PeHeaders,
+    scan::Pattern,
+};
+use serde::{Deserialize, Serialize};
+
+const USAGE: &str = "\
+Usage: ds2s_heap_x_patcher [OPTIONS] <DarkSoulsII.exe>
+
+Options:
+  --config <PATH>   heap_x config, defaults to \"ds2s_heap_x.toml\" next to the executable,
+                    read with the \"ds2s_heap_x.d\" fragments and \"ds2s_heap_x.user.toml\"
+                    next to it
+  --patches <PATH>  patch set, defaults to the embedded one for the executable version
+  --output <PATH>   where to write the patched executable, defaults to the input
+  --restore         reverse the patches recorded in the journal of <DarkSoulsII.exe>
+  --dry-run         list every patch without writing anything, like `dry_run = true`
+  --force           patch even if the executable or the output already has a journal,
+                    restoring the original bytes it records before patching again
+  --signatures      print a signature uniquely matching every `rva` site of the patch set
+                    in <DarkSoulsII.exe>, to replace the `rva` of the site with
+  --help            print this message
+  --heapx-<KEY>=<VALUE>
+                    override a config key, like the game command line";
+
+struct Args {
+    exe_path: PathBuf,
+    config_path: Option<PathBuf>,
+    patch_set_path: Option<PathBuf>,
+    output_path: Option<PathBuf>,
+    restore: bool,
+    dry_run: bool,
+    force: bool,
+    signatures: bool,
+}
+
+/// The original and patched bytes of every written site.
+#[derive(Default, Serialize, Deserialize)]
+struct Journal {
+    #[serde(default, rename = "site")]
+    sites: Vec<JournalSite>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalSite {
+    rva: usize,
+    original: Vec<u8>,
+    patched: Vec<u8>,
+}
+
+/// Records the original bytes of every site written through it.
+struct JournalMemory<'a> {
+    memory: &'a mut FileMemory,
+    journal: Journal,
+}
+
+impl Memory for JournalMemory<'_> {
+    fn read(&self, rva: usize, buf: &mut [u8]) -> Result<(), MemoryError> {
+        self.memory.read(rva, buf)
+    }
+
+    fn write(&mut self, rva: usize, bytes: &[u8]) -> Result<(), MemoryError> {
+        if !self.journal.sites.iter().any(|site| site.rva == rva) {
+            let mut original = vec![0; bytes.len()];
+
+            self.memory.read(rva, &mut original)?;
+
+            self.journal.sites.push(JournalSite {
+                rva,
+                original,
+                patched: Vec::new(),
+            });
+        }
+
+        self.memory.write(rva, bytes)
+    }
+
+    fn protect(&mut self, rva: usize, len: usize) -> Result<(), MemoryError> {
+        self.memory.protect(rva, len)
+    }
+
+    fn bytes(&self) -> Result<(usize, &[u8]), MemoryError> {
+        self.memory.bytes()
+    }
+
+    fn headers(&self) -> Option<&PeHeaders> {
+        self.memory.headers()
+    }
+
+    fn available_memory(&self) -> Option<u64> {
+        self.memory.available_memory()
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = if args.restore {
+        restore(&args)
+    } else if args.signatures {
+        signatures(&args)
+    } else {
+        patch(&args)
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut exe_path = None;
+    let mut config_path = None;
+    let mut patch_set_path = None;
+    let mut output_path = None;
+    let mut restore = false;
+    let mut dry_run = false;
+    let mut force = false;
+    let mut signatures = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .map(PathBuf::from)
+                .ok_or_else(|| format!("{name} requires a value"))
+        };
+
+        match arg.as_str() {
+            "--config" => config_path = Some(value("--config")?),
+            "--patches" => patch_set_path = Some(value("--patches")?),
+            "--output" => output_path = Some(value("--output")?),
+            "--restore" => restore = true,
+            "--dry-run" => dry_run = true,
+            "--force" => force = true,
+            "--signatures" => signatures = true,
+            "--help" | "-h" => return Ok(None),
+            // Read with the other overrides.
+            _ if arg.starts_with(ARG_PREFIX) => {}
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ if exe_path.is_none() => exe_path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
+    }
+
+    let exe_path = exe_path.ok_or("missing executable path")?;
+
+    Ok(Some(Args {
+        exe_path,
+        config_path,
+        patch_set_path,
+        output_path,
+        restore,
+        dry_run,
+        force,
+        signatures,
+    }))
+}
+
+fn patch(args: &Args) -> Result<(), String> {
+    let mut config = read_config(args)?;
+
+    config.dry_run |= args.dry_run;
+
+    let output_path = args.output_path.as_deref().unwrap_or(&args.exe_path);
+    let output_journal_path = journal_path(output_path);
+
+    for path in [output_path, &args.exe_path] {
+        if journal_path(path).exists() && !args.force && !config.dry_run {
+            return Err(format!(
+                "{} already exists, {} is already patched: run with --restore first, or with \
+                 --force to patch it again",
+                journal_path(path).display(),
+                path.display()
+            ));
+        }
+    }
+
+    let mut memory = read_exe(&args.exe_path)?;
+
+    // Patching from the original bytes, so the sites are not patched twice and the new journal
+    // records the original bytes instead of the patched ones.
+    let exe_journal_path = journal_path(&args.exe_path);
+
+    if exe_journal_path.exists() {
+        let journal = read_journal(&exe_journal_path)?;
+
+        unpatch(&mut memory, &journal)?;
+
+        println!(
+            "restored {} site(s) recorded in {} before patching",
+            journal.sites.len(),
+            exe_journal_path.display()
+        );
+    }
+
+    let (version, patch_set) = read_patch_set(args, &memory)?;
+
+    let status = builds::identify(&patch_set, memory.as_bytes(), config.allow_unknown_builds)
+        .map_err(|err| err.to_string())?;
+
+    println!("DarkSoulsII.exe {version}: {status}");
+
+    let mut journal_memory = JournalMemory {
+        memory: &mut memory,
+        journal: Journal::default(),
+    };
+
+    let report = patches::place_all(&mut journal_memory, &config, &patch_set)
+        .map_err(|err| err.to_string())?;
+
+    println!("heaps: {}", report.budget);
+
+    let unchecked = report.sites.iter().filter(|site| !site.checked).count();
+
+    if unchecked > 0 {
+        eprintln!(
+            "warning: {unchecked} site(s) have no recorded vanilla value and are not checked"
+        );
+    }
+
+    for (group, state) in &report.groups {
+        if *state != GroupState::Enabled {
+            println!("{group}: skipped, {state}");
+        }
+    }
+
+    if config.dry_run {
+        println!(
+            "dry run, nothing was written:\n{}",
+            SiteTable(&report.sites)
+        );
+
+        return Ok(());
+    }
+
+    let mut journal = journal_memory.journal;
+
+    for site in &mut journal.sites {
+        site.patched = vec![0; site.original.len()];
+
+        memory
+            .read(site.rva, &mut site.patched)
+            .map_err(|err| err.to_string())?;
+    }
+
+    backup(&args.exe_path)?;
+
+    write_file(output_path, &memory.into_bytes())?;
+
+    let contents = toml::to_string(&journal).expect("valid journal toml");
+
+    write_file(&output_journal_path, contents.as_bytes())?;
+
+    println!(
+        "patched {} site(s) in {}",
+        journal.sites.len(),
+        output_path.display()
+    );
+
+    Ok(())
+}
+
+fn restore(args: &Args) -> Result<(), String> {
+    let journal_path = journal_path(&args.exe_path);
+
+    let journal = read_journal(&journal_path)?;
+
+    let mut memory = read_exe(&args.exe_path)?;
+
+    unpatch(&mut memory, &journal)?;
+
+    let output_path = args.output_path.as_deref().unwrap_or(&args.exe_path);
+
+    write_file(output_path, &memory.into_bytes())?;
+
+    if output_path == args.exe_path {
+        let _ = fs::remove_file(&journal_path);
+    }
+
+    println!(
+        "restored {} site(s) in {}",
+        journal.sites.len(),
+        output_path.display()
+    );
+
+    Ok(())
+}
+
+/// The longest signature tried for a site, longer ones are too fragile to be worth it.
+const MAX_SIGNATURE_LEN: usize = 64;
+
+/// Prints the shortest signature uniquely matching every `rva` site, with the patched operand
+/// matching any byte, for the patch set to keep working when the executable is rebuilt.
+fn signatures(args: &Args) -> Result<(), String> {
+    let memory = read_exe(&args.exe_path)?;
+
+    let (version, patch_set) = read_patch_set(args, &memory)?;
+
+    let (base_rva, text) = memory.bytes().map_err(|err| err.to_string())?;
+
+    println!("# DarkSoulsII.exe {version}");
+
+    for patch in &patch_set.patches {
+        let Some(rva) = patch.rva.filter(|_| patch.signature.is_none()) else {
+            continue;
+        };
+
+        println!("\n# {}: {}", patch.group, patch.name);
+
+        let Some(offset) = rva
+            .checked_sub(base_rva)
+            .filter(|offset| *offset < text.len())
+        else {
+            println!("# {rva:#x} is not in .text, keeping `rva`");
+            continue;
+        };
+
+        // The operand holds another value in patched executables.
+        let wildcards = match patch.op {
+            PatchOp::Nop => 0..0,
+            _ => patch.operand..patch.operand + 4,
+        };
+
+        match Pattern::unique_at(text, offset, wildcards, MAX_SIGNATURE_LEN) {
+            Ok(pattern) => {
+                println!("signature = \"{pattern}\"");
+
+                if patch.operand != 0 {
+                    println!("operand = {}", patch.operand);
+                }
+            }
+            Err(err) => println!("# {rva:#x}: {err}, keeping `rva`"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the version of the executable and the patch set for it.
+fn read_patch_set(args: &Args, memory: &FileMemory) -> Result<(Version, PatchSet), String> {
+    let version = Version::read(memory).ok_or_else(|| {
+        format!(
+            "{}: failed to read the executable version",
+            args.exe_path.display()
+        )
+    })?;
+
+    let patch_set = match &args.patch_set_path {
+        Some(path) => PatchSet::read(path)
+            .and_then(|patch_set| patch_set.check_version(&version))
+            .map_err(|err| format!("{}: {err}", path.display()))?,
+        None => PatchSet::embedded(&version).map_err(|err| err.to_string())?,
+    };
+
+    Ok((version, patch_set))
+}
+
+fn read_journal(journal_path: &Path) -> Result<Journal, String> {
+    let journal = fs::read_to_string(journal_path)
+        .map_err(|err| format!("{}: {err}", journal_path.display()))?;
+
+    toml::from_str::<Journal>(&journal).map_err(|err| format!("{}: {err}", journal_path.display()))
+}
+
+/// Writes back the original bytes of every site in `journal`, if all of them still hold the
+/// bytes written by the patcher.
+fn unpatch(memory: &mut FileMemory, journal: &Journal) -> Result<(), String> {
+    for site in &journal.sites {
+        let mut found = vec![0; site.patched.len()];
+
+        memory
+            .read(site.rva, &mut found)
+            .map_err(|err| err.to_string())?;
+
+        if found != site.patched {
+            return Err(format!(
+                "DarkSoulsII.exe+{:#x} was modified after patching, not restoring",
+                site.rva
+            ));
+        }
+    }
+
+    for site in journal.sites.iter().rev() {
+        memory
+            .write(site.rva, &site.original)
+            .map_err(|err| err.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Reads the config layers like the DLL, with the config as the base layer and the fragments
+/// and user config next to it.
+fn read_config(args: &Args) -> Result<Config, String> {
+    let config_path = match &args.config_path {
+        Some(path) => path.clone(),
+        None => args.exe_path.with_file_name(BASE_CONFIG),
+    };
+
+    let mut log = Log::stderr();
+
+    let base = match Config::read_partial(&config_path) {
+        Ok((config, table, warnings)) => {
+            log.write(format_args!("config: read {}", config_path.display()));
+
+            for warning in warnings {
+                log.write(format_args!("config: warning: {warning}"));
+            }
+
+            Some((config, table))
+        }
+        Err(ConfigError::FileNotFound) if args.config_path.is_none() => None,
+        Err(err) => return Err(format!("{}: {err}", config_path.display())),
+    };
+
+    let dir = match config_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let base_layer = config_path
+        .file_name()
+        .map_or(BASE_CONFIG.into(), |file_name| file_name.to_string_lossy());
+
+    Ok(layers::read_layers(dir, &base_layer, base, &mut log))
+}
+
+fn read_exe(exe_path: &Path) -> Result<FileMemory, String> {
+    let bytes = fs::read(exe_path).map_err(|err| format!("{}: {err}", exe_path.display()))?;
+
+    let headers =
+        PeHeaders::parse(&bytes).map_err(|err| format!("{}: {err}", exe_path.display()))?;
+
+    Ok(FileMemory::new(headers, bytes))
+}
+
+/// Copies the executable to "<name>.bak", unless a backup already exists.
+fn backup(exe_path: &Path) -> Result<(), String> {
+    let mut backup_path = exe_path.as_os_str().to_owned();
+    backup_path.push(".bak");
+
+    let backup_path = PathBuf::from(backup_path);
+
+    if backup_path.exists() {
+        return Ok(());
+    }
+
+    fs::copy(exe_path, &backup_path)
+        .map(|_| ())
+        .map_err(|err| format!("{}: {err}", backup_path.display()))
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> Result<(), String> {
+    fs::write(path, contents).map_err(|err| format!("{}: {err}", path.display()))
+}
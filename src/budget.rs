@@ -0,0 +1,325 @@
+use std::{collections::HashMap, fmt};
+
+use crate::config::{BudgetAction, BudgetConfig, HeapSize, HeapSizeConfig};
+
+/// The vanilla size of a heap resized by the patch set, read from its first site.
+pub struct HeapBase {
+    pub heap: String,
+    pub base: u32,
+    /// Whether the heap is allocated from the global heap, which grows with it.
+    pub global_heap: bool,
+}
+
+/// The heap sizes to patch, scaled to fit the budget if necessary.
+pub struct HeapPlan {
+    /// The heaps whose configured size was scaled back.
+    pub sizes: HashMap<String, HeapSize>,
+    pub report: BudgetReport,
+}
+
+/// How much memory the heaps need, written to the log.
+pub struct BudgetReport {
+    /// The total size of the configured heaps.
+    pub requested: u64,
+    /// The total size of the patched heaps.
+    pub total: u64,
+    /// The smaller of the configured and the available memory, if either is known.
+    pub budget: Option<u64>,
+    /// The factor the heap growth was scaled by, 1 if the heaps fit.
+    pub scale: f64,
+}
+
+impl fmt::Display for BudgetReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in total", Bytes(self.total))?;
+
+        if let Some(budget) = self.budget {
+            write!(f, ", budget {}", Bytes(budget))?;
+        }
+
+        if self.scale < 1.0 {
+            write!(
+                f,
+                ", {} requested, heap growth scaled to {:.1}%",
+                Bytes(self.requested),
+                self.scale * 100.0
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum BudgetError {
+    /// The heaps need more memory than the budget and `exceeded` is "refuse".
+    Exceeded { requested: u64, budget: u64 },
+}
+
+impl fmt::Display for BudgetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exceeded { requested, budget } => write!(
+                f,
+                "the heaps need {} in total, more than the budget of {}",
+                Bytes(*requested),
+                Bytes(*budget)
+            ),
+        }
+    }
+}
+
+/// Fits the configured heap sizes in the budget.
+///
+/// The heaps allocated from the global heap are counted as a part of it. If the heaps do not
+/// fit in the budget, the growth of every heap is scaled by the same factor, or the heaps are
+/// refused, as configured. Heaps too large for their 32-bit size operand are rejected by
+/// `place_all` before planning, the budget never shrinks them to fit.
+pub fn plan(
+    heaps: &[HeapBase],
+    global_base: Option<u32>,
+    sizes: &HeapSizeConfig,
+    config: &BudgetConfig,
+    available: Option<u64>,
+) -> Result<HeapPlan, BudgetError> {
+    let size_of = |heap: &str| sizes.get(heap).unwrap_or(HeapSize::Multiplier(1.0));
+
+    // Heaps and their growth at full size, the global heap last.
+    let mut growths = Vec::new();
+    let mut vanilla = 0u64;
+    let mut global_heap_growth = 0u64;
+
+    for HeapBase {
+        heap,
+        base,
+        global_heap,
+    } in heaps
+    {
+        let growth = size_of(heap).size(*base) - u64::from(*base);
+
+        if *global_heap {
+            global_heap_growth = global_heap_growth.saturating_add(growth);
+        } else {
+            vanilla += u64::from(*base);
+        }
+
+        growths.push((heap.as_str(), *base, growth, *global_heap));
+    }
+
+    if let Some(base) = global_base {
+        let growth = size_of("global").size(base) - u64::from(base);
+
+        vanilla += u64::from(base);
+
+        growths.push(("global", base, growth.max(global_heap_growth), false));
+    }
+
+    let growth = growths
+        .iter()
+        .filter(|(.., global_heap)| !global_heap)
+        .fold(0u64, |sum, &(_, _, growth, _)| sum.saturating_add(growth));
+
+    let requested = vanilla.saturating_add(growth);
+
+    let budget = match (config.max_total, available) {
+        (Some(max_total), Some(available)) => Some(max_total.min(available)),
+        (max_total, available) => max_total.or(available),
+    };
+
+    let mut scale = 1.0f64;
+
+    // The vanilla heaps are reserved whether heap_x patches them or not, only their growth
+    // can be refused.
+    if let Some(budget) = budget.filter(|&budget| growth > budget.saturating_sub(vanilla)) {
+        if config.exceeded == BudgetAction::Refuse {
+            return Err(BudgetError::Exceeded { requested, budget });
+        }
+
+        scale = budget.saturating_sub(vanilla) as f64 / growth as f64;
+    }
+
+    let mut scaled_sizes = HashMap::new();
+
+    if scale < 1.0 {
+        for &(heap, base, growth, _) in &growths {
+            if base == 0 {
+                continue;
+            }
+
+            // Relative to the first site, so the other sites of the heap are scaled alike.
+            let size = u64::from(base) + (growth as f64 * scale) as u64;
+
+            scaled_sizes.insert(
+                heap.to_owned(),
+                HeapSize::Multiplier(size as f64 / f64::from(base)),
+            );
+        }
+    }
+
+    let total = vanilla + (growth as f64 * scale) as u64;
+
+    Ok(HeapPlan {
+        sizes: scaled_sizes,
+        report: BudgetReport {
+            requested,
+            total,
+            budget,
+            scale,
+        },
+    })
+}
+
+/// A byte count with a binary unit, "1.5GiB".
+struct Bytes(u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [(&str, u64); 3] = [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)];
+
+        match UNITS.into_iter().find(|&(_, scale)| self.0 >= scale) {
+            Some((unit, scale)) => write!(f, "{:.1}{unit}", self.0 as f64 / scale as f64),
+            None => write!(f, "{}B", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u32 = 1 << 20;
+
+    fn sound_heap(base: u32) -> Vec<HeapBase> {
+        vec![HeapBase {
+            heap: "sound".to_owned(),
+            base,
+            global_heap: false,
+        }]
+    }
+
+    fn sizes(sound: HeapSize) -> HeapSizeConfig {
+        HeapSizeConfig {
+            sound,
+            ..Default::default()
+        }
+    }
+
+    fn budget(max_total: Option<u64>, exceeded: BudgetAction) -> BudgetConfig {
+        BudgetConfig {
+            max_total,
+            exceeded,
+        }
+    }
+
+    #[test]
+    fn keeps_heaps_that_fit() {
+        let plan = plan(
+            &sound_heap(100 * MIB),
+            None,
+            &sizes(HeapSize::Multiplier(2.0)),
+            &budget(Some(300 << 20), BudgetAction::Refuse),
+            None,
+        )
+        .unwrap();
+
+        assert!(plan.sizes.is_empty());
+        assert_eq!(plan.report.scale, 1.0);
+        assert_eq!(plan.report.total, 200 << 20);
+    }
+
+    #[test]
+    fn scales_growth_to_budget() {
+        let plan = plan(
+            &sound_heap(100 * MIB),
+            None,
+            &sizes(HeapSize::Multiplier(3.0)),
+            &budget(Some(200 << 20), BudgetAction::Scale),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(plan.report.requested, 300 << 20);
+        assert_eq!(plan.report.scale, 0.5);
+        assert_eq!(plan.report.total, 200 << 20);
+        assert_eq!(plan.sizes["sound"], HeapSize::Multiplier(2.0));
+    }
+
+    #[test]
+    fn uses_smaller_of_max_total_and_available() {
+        let plan = plan(
+            &sound_heap(100 * MIB),
+            None,
+            &sizes(HeapSize::Multiplier(3.0)),
+            &budget(Some(1 << 30), BudgetAction::Scale),
+            Some(150 << 20),
+        )
+        .unwrap();
+
+        assert_eq!(plan.report.budget, Some(150 << 20));
+        assert_eq!(plan.report.scale, 0.25);
+    }
+
+    #[test]
+    fn refuses_growth_over_budget() {
+        let err = plan(
+            &sound_heap(100 * MIB),
+            None,
+            &sizes(HeapSize::Multiplier(3.0)),
+            &budget(Some(200 << 20), BudgetAction::Refuse),
+            None,
+        )
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            err,
+            BudgetError::Exceeded {
+                requested,
+                budget
+            } if requested == 300 << 20 && budget == 200 << 20
+        ));
+    }
+
+    #[test]
+    fn never_refuses_vanilla_heaps() {
+        let plan = plan(
+            &sound_heap(100 * MIB),
+            Some(50 * MIB),
+            &sizes(HeapSize::Multiplier(1.0)),
+            &budget(Some(1 << 20), BudgetAction::Refuse),
+            Some(1 << 20),
+        )
+        .unwrap();
+
+        assert!(plan.sizes.is_empty());
+        assert_eq!(plan.report.total, 150 << 20);
+    }
+
+    #[test]
+    fn counts_global_heap_growth_once() {
+        let heaps = vec![HeapBase {
+            heap: "sound".to_owned(),
+            base: 100 * MIB,
+            global_heap: true,
+        }];
+
+        let plan = plan(
+            &heaps,
+            Some(500 * MIB),
+            &sizes(HeapSize::Multiplier(2.0)),
+            &BudgetConfig::default(),
+            None,
+        )
+        .unwrap();
+
+        // The sound heap grows the global heap by 100MiB, it is not counted on its own.
+        assert_eq!(plan.report.total, 600 << 20);
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(Bytes(512).to_string(), "512B");
+        assert_eq!(Bytes(1536).to_string(), "1.5KiB");
+        assert_eq!(Bytes(3 << 30).to_string(), "3.0GiB");
+    }
+}
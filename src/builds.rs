@@ -0,0 +1,278 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    definitions::{PatchSet, PatchSetError},
+    memory::Memory,
+};
+
+/// A game build heap_x has an embedded patch set for.
+pub struct Build {
+    pub version: Version,
+    /// The patch set TOML, see "patches/1.0.3.0.toml" for the format.
+    pub patch_set: &'static str,
+}
+
+/// Every supported game build. A build is added by writing its patch set file,
+/// with its own RVAs and layout constants, and registering it here.
+pub const BUILDS: &[Build] = &[Build {
+    version: Version::new(1, 0, 3, 0),
+    patch_set: include_str!("../patches/1.0.3.0.toml"),
+}];
+
+pub fn find(version: &Version) -> Option<&'static Build> {
+    BUILDS.iter().find(|build| build.version == *version)
+}
+
+/// How DarkSoulsII.exe matches the builds heap_x has patch sets for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildStatus {
+    /// Both the version and the executable hash match a patch set.
+    Known,
+    /// The version matches a patch set, but the executable is not one it was validated against.
+    VersionMatchUnknownHash,
+    Unknown,
+}
+
+impl fmt::Display for BuildStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Known => f.write_str("known"),
+            Self::VersionMatchUnknownHash => f.write_str("version match but unknown hash"),
+            Self::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+/// Identifies the executable by the SHA-256 hash of its file, `exe`.
+///
+/// Executables whose hash the patch set does not list, including every executable if it lists
+/// none, are refused unless `allow_unknown`. They are then only validated by the expected
+/// original values of the patch sites.
+pub fn identify(
+    patch_set: &PatchSet,
+    exe: &[u8],
+    allow_unknown: bool,
+) -> Result<BuildStatus, PatchSetError> {
+    let sha256 = sha256_hex(exe);
+
+    if patch_set.sha256.contains(&sha256) {
+        Ok(BuildStatus::Known)
+    } else if allow_unknown {
+        Ok(BuildStatus::VersionMatchUnknownHash)
+    } else {
+        Err(PatchSetError::UnknownHash {
+            version: patch_set.version,
+            sha256,
+        })
+    }
+}
+
+/// "<name>.heap_x.toml" next to an executable patched by `ds2s_heap_x_patcher`, with the
+/// original bytes of every patched site.
+pub fn journal_path(exe_path: &Path) -> PathBuf {
+    let mut journal_path = exe_path.as_os_str().to_owned();
+    journal_path.push(".heap_x.toml");
+
+    PathBuf::from(journal_path)
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The file version of DarkSoulsII.exe, from its `VS_FIXEDFILEINFO` resource.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+    pub revision: u16,
+    pub build: u16,
+}
+
+impl Version {
+    pub const fn new(major: u16, minor: u16, revision: u16, build: u16) -> Self {
+        Self {
+            major,
+            minor,
+            revision,
+            build,
+        }
+    }
+
+    /// Reads the file version from the version resource of the image in `memory`.
+    ///
+    /// Returns `None` if the memory has no PE headers or the image has no version resource.
+    pub fn read<M: Memory>(memory: &M) -> Option<Self> {
+        const RT_VERSION: u32 = 16;
+        const VS_VERSION_INFO: u32 = 1;
+        const VERSION_SIGNATURE: u32 = 0xFEEF04BD;
+
+        let resources = memory.headers()?.resource_rva as usize;
+
+        if resources == 0 {
+            return None;
+        }
+
+        // The version resource is the first language of RT_VERSION\VS_VERSION_INFO.
+        let entry = find_resource(memory, resources, resources, Some(RT_VERSION))?;
+        let entry = find_resource(memory, resources, entry, Some(VS_VERSION_INFO))?;
+        let entry = find_resource(memory, resources, entry, None)?;
+
+        // IMAGE_RESOURCE_DATA_ENTRY::OffsetToData, Size
+        let data = memory.read_u32(entry).ok()? as usize;
+        let size = memory.read_u32(entry + 4).ok()? as usize;
+
+        let mut resource = vec![0; size];
+        memory.read(data, &mut resource).ok()?;
+
+        let info = resource
+            .chunks_exact(4)
+            .position(|word| word == VERSION_SIGNATURE.to_le_bytes())?
+            * 4;
+
+        // VS_FIXEDFILEINFO::dwFileVersionMS, dwFileVersionLS
+        let version = resource.get(info + 8..info + 16)?;
+
+        let word = |i: usize| u16::from_le_bytes([version[i], version[i + 1]]);
+
+        Some(Self::new(word(2), word(0), word(6), word(4)))
+    }
+}
+
+/// Finds the entry with `id` in the resource directory at `dir`, or its first entry if `id` is `None`,
+/// and returns the RVA of the subdirectory or data entry it points to.
+fn find_resource<M: Memory>(
+    memory: &M,
+    resources: usize,
+    dir: usize,
+    id: Option<u32>,
+) -> Option<usize> {
+    const IMAGE_RESOURCE_DATA_IS_DIRECTORY: u32 = 0x8000_0000;
+
+    // IMAGE_RESOURCE_DIRECTORY::NumberOfNamedEntries, NumberOfIdEntries
+    let counts = memory.read_u32(dir + 12).ok()?;
+    let count = (counts & 0xFFFF) as usize + (counts >> 16) as usize;
+
+    (0..count).find_map(|i| {
+        let entry = dir + 16 + i * 8;
+
+        let name = memory.read_u32(entry).ok()?;
+        let offset = memory.read_u32(entry + 4).ok()?;
+
+        id.is_none_or(|id| name == id)
+            .then_some(resources + (offset & !IMAGE_RESOURCE_DATA_IS_DIRECTORY) as usize)
+    })
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.major, self.minor, self.revision, self.build
+        )
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .split('.')
+            .map(str::parse)
+            .collect::<Result<Vec<u16>, _>>()
+            .map_err(|_| format!("invalid version \"{s}\""))?;
+
+        match parts[..] {
+            [major, minor, revision, build] => Ok(Self::new(major, minor, revision, build)),
+            _ => Err(format!("invalid version \"{s}\", expected four numbers")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::PatchSet;
+
+    fn patch_set(sha256: &[&str]) -> PatchSet {
+        format!("version = \"1.0.3.0\"\nsha256 = {sha256:?}\n")
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn embeds_a_valid_patch_set_per_build() {
+        for build in BUILDS {
+            let patch_set = PatchSet::embedded(&build.version).unwrap();
+
+            assert_eq!(patch_set.version, build.version);
+        }
+    }
+
+    #[test]
+    fn identifies_by_hash() {
+        let exe = b"DarkSoulsII.exe";
+        let sha256 = sha256_hex(exe);
+
+        assert_eq!(
+            identify(&patch_set(&[&sha256]), exe, false).unwrap(),
+            BuildStatus::Known
+        );
+        assert!(matches!(
+            identify(&patch_set(&[&sha256]), b"repacked", false),
+            Err(PatchSetError::UnknownHash { .. })
+        ));
+        assert_eq!(
+            identify(&patch_set(&[&sha256]), b"repacked", true).unwrap(),
+            BuildStatus::VersionMatchUnknownHash
+        );
+    }
+
+    #[test]
+    fn refuses_every_build_without_hashes_unless_allowed() {
+        assert!(matches!(
+            identify(&patch_set(&[]), b"DarkSoulsII.exe", false),
+            Err(PatchSetError::UnknownHash { .. })
+        ));
+        assert_eq!(
+            identify(&patch_set(&[]), b"DarkSoulsII.exe", true).unwrap(),
+            BuildStatus::VersionMatchUnknownHash
+        );
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!("1.0.3.0".parse(), Ok(Version::new(1, 0, 3, 0)));
+        assert_eq!(Version::new(1, 0, 3, 0).to_string(), "1.0.3.0");
+        assert!("1.0.3".parse::<Version>().is_err());
+        assert!("1.0.3.x".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn journal_is_next_to_the_executable() {
+        assert_eq!(
+            journal_path(Path::new("game/DarkSoulsII.exe")),
+            Path::new("game/DarkSoulsII.exe.heap_x.toml")
+        );
+    }
+}
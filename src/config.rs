@@ -1,48 +1,96 @@
 use std::{
-    fs, io,
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt, fs, io,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::{
+    log::Log,
+    migration::{self, CONFIG_VERSION},
+    presets::Preset,
+};
+
+/// The config written when there is none, documenting every key. Its values are the defaults.
+pub const DEFAULT_CONFIG: &str = include_str!("../config/ds2s_heap_x.toml");
+
+/// Every missing key falls back to its default value, so configs written by older
+/// versions of heap_x stay valid.
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    /// The config format version, configs without it predate it and are version 1.
+    #[serde(default = "migration::legacy_config_version")]
+    pub config_version: u32,
+    /// Fills in every key that is not set in the config.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<Preset>,
     pub patch_character_limit: bool,
     pub patch_soundbank_limit: bool,
+    pub patch_morpheme_limit: bool,
+    pub patch_map_dtor_stack: bool,
+    pub patch_enemy_generator_limit: bool,
+    /// Whether each heap is resized, by `HeapSizeConfig` key. Missing heaps are resized.
+    pub patch_heaps: BTreeMap<String, bool>,
+    /// Computes and reports every patch without writing to the game.
+    pub dry_run: bool,
+    /// Patches executables whose hash the patch set does not list.
+    pub allow_unknown_builds: bool,
     pub heap_size_multiplier: u32,
     pub heap_sizes: HeapSizeConfig,
+    pub limits: LimitsConfig,
+    pub budget: BudgetConfig,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct HeapSizeConfig {
-    pub debug: u32,
-    pub facegen: u32,
-    pub file_data: u32,
-    pub global: u32,
-    pub graphics: u32,
-    pub gui: u32,
-    pub havok: u32,
-    pub menu: u32,
-    pub morpheme: u32,
-    pub network: u32,
-    pub player: u32,
-    pub regulation: u32,
-    pub scene_graph: u32,
-    pub sfx: u32,
-    pub sound: u32,
-    pub string_data: u32,
-    pub system: u32,
-    pub temp: u32,
-    pub temp2: u32,
+    pub debug: HeapSize,
+    pub facegen: HeapSize,
+    pub file_data: HeapSize,
+    pub global: HeapSize,
+    pub graphics: HeapSize,
+    pub gui: HeapSize,
+    pub havok: HeapSize,
+    pub menu: HeapSize,
+    pub morpheme: HeapSize,
+    pub network: HeapSize,
+    pub player: HeapSize,
+    pub regulation: HeapSize,
+    pub scene_graph: HeapSize,
+    pub sfx: HeapSize,
+    pub sound: HeapSize,
+    pub string_data: HeapSize,
+    pub system: HeapSize,
+    pub temp: HeapSize,
+    pub temp2: HeapSize,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            config_version: CONFIG_VERSION,
+            preset: None,
             patch_character_limit: true,
             patch_soundbank_limit: true,
+            patch_morpheme_limit: true,
+            patch_map_dtor_stack: true,
+            patch_enemy_generator_limit: true,
+            // Every heap heap_x resizes.
+            patch_heaps: HeapSizeConfig::NAMES
+                .into_iter()
+                .filter(|&name| name != "system")
+                .map(|name| (name.to_owned(), true))
+                .collect(),
+            dry_run: false,
+            allow_unknown_builds: false,
             heap_size_multiplier: 2,
             heap_sizes: Default::default(),
+            limits: Default::default(),
+            budget: Default::default(),
         }
     }
 }
@@ -50,190 +98,894 @@ impl Default for Config {
 impl Default for HeapSizeConfig {
     fn default() -> Self {
         Self {
-            debug: 1,
-            facegen: 1,
-            file_data: 2,
-            global: 1,
-            graphics: 1,
-            gui: 1,
-            havok: 4,
-            menu: 1,
-            morpheme: 4,
-            network: 1,
-            player: 1,
-            regulation: 2,
-            scene_graph: 1,
-            sfx: 4,
-            sound: 3,
-            string_data: 2,
-            system: 2,
-            temp: 1,
-            temp2: 1,
+            debug: HeapSize::Multiplier(1.0),
+            facegen: HeapSize::Multiplier(1.0),
+            file_data: HeapSize::Multiplier(2.0),
+            global: HeapSize::Multiplier(1.0),
+            graphics: HeapSize::Multiplier(1.0),
+            gui: HeapSize::Multiplier(1.0),
+            havok: HeapSize::Multiplier(4.0),
+            menu: HeapSize::Multiplier(1.0),
+            morpheme: HeapSize::Multiplier(4.0),
+            network: HeapSize::Multiplier(1.0),
+            player: HeapSize::Multiplier(1.0),
+            regulation: HeapSize::Multiplier(2.0),
+            scene_graph: HeapSize::Multiplier(1.0),
+            sfx: HeapSize::Multiplier(4.0),
+            sound: HeapSize::Multiplier(3.0),
+            string_data: HeapSize::Multiplier(2.0),
+            system: HeapSize::Multiplier(2.0),
+            temp: HeapSize::Multiplier(1.0),
+            temp2: HeapSize::Multiplier(1.0),
+        }
+    }
+}
+
+impl HeapSizeConfig {
+    /// Every heap size key, in config order.
+    pub const NAMES: [&'static str; 19] = [
+        "debug",
+        "facegen",
+        "file_data",
+        "global",
+        "graphics",
+        "gui",
+        "havok",
+        "menu",
+        "morpheme",
+        "network",
+        "player",
+        "regulation",
+        "scene_graph",
+        "sfx",
+        "sound",
+        "string_data",
+        "system",
+        "temp",
+        "temp2",
+    ];
+
+    /// Looks up a heap size by its key, as used by the patch set `heap` patches.
+    pub fn get(&self, name: &str) -> Option<HeapSize> {
+        match name {
+            "debug" => Some(self.debug),
+            "facegen" => Some(self.facegen),
+            "file_data" => Some(self.file_data),
+            "global" => Some(self.global),
+            "graphics" => Some(self.graphics),
+            "gui" => Some(self.gui),
+            "havok" => Some(self.havok),
+            "menu" => Some(self.menu),
+            "morpheme" => Some(self.morpheme),
+            "network" => Some(self.network),
+            "player" => Some(self.player),
+            "regulation" => Some(self.regulation),
+            "scene_graph" => Some(self.scene_graph),
+            "sfx" => Some(self.sfx),
+            "sound" => Some(self.sound),
+            "string_data" => Some(self.string_data),
+            "system" => Some(self.system),
+            "temp" => Some(self.temp),
+            "temp2" => Some(self.temp2),
+            _ => None,
+        }
+    }
+}
+
+/// Capacities of the fixed size containers patched by the limit groups.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Character types loaded at once, see `ResObjectHolder`.
+    pub character: u32,
+    /// Non-persistent FMod soundbanks loaded at once, see `RegisteredBankHolder`.
+    pub soundbank: u32,
+    /// Morpheme data count, scaled with the morpheme heap if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub morpheme: Option<u32>,
+    /// Enemies per map, see the map destructor stack array.
+    pub map_enemy: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            character: 1024,
+            soundbank: 513,
+            morpheme: None,
+            map_enemy: 1024,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// The vanilla capacity and the largest accepted one of each limit.
+    pub const RANGES: [(&'static str, u32, u32); 4] = [
+        // `ResObjectHolder` grows by 32 bytes per character.
+        ("character", 32, 4096),
+        // `RegisteredBankHolder` grows by 632 bytes per soundbank,
+        // and the game ships with fewer than 513 soundbanks.
+        ("soundbank", 48, 1024),
+        // The morpheme data grows by 40 bytes per element.
+        ("morpheme", 0x3000, 0x30000),
+        // The map destructor stack frame grows by 52 bytes per enemy,
+        // 4096 enemies take 200 KiB of the 1 MiB main thread stack.
+        ("map_enemy", 256, 4096),
+    ];
+
+    /// Looks up a limit by its key, as used by the patch set expressions as `limits.<name>`.
+    pub fn get(&self, name: &str) -> Option<u32> {
+        match name {
+            "character" => Some(self.character),
+            "soundbank" => Some(self.soundbank),
+            "morpheme" => self.morpheme,
+            "map_enemy" => Some(self.map_enemy),
+            _ => None,
+        }
+    }
+
+    /// Clamps every limit to its range in `RANGES`.
+    fn normalize(self) -> Self {
+        let clamp = |name: &str, val: u32| {
+            let (_, min, max) = Self::RANGES
+                .into_iter()
+                .find(|(key, ..)| *key == name)
+                .expect("limit range");
+
+            val.clamp(min, max)
+        };
+
+        Self {
+            character: clamp("character", self.character),
+            soundbank: clamp("soundbank", self.soundbank),
+            morpheme: self.morpheme.map(|morpheme| clamp("morpheme", morpheme)),
+            map_enemy: clamp("map_enemy", self.map_enemy),
+        }
+    }
+}
+
+/// Keeps the total size of the heaps within the memory the game can use.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BudgetConfig {
+    /// The most memory the heaps may use in total, in bytes, on top of the memory available
+    /// when the game starts.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_size",
+        deserialize_with = "deserialize_size"
+    )]
+    pub max_total: Option<u64>,
+    pub exceeded: BudgetAction,
+}
+
+/// What to do when the heaps do not fit in the budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetAction {
+    /// Shrinks the growth of every heap by the same factor.
+    #[default]
+    Scale,
+    /// Patches nothing, the game runs with its vanilla heaps.
+    Refuse,
+}
+
+fn serialize_size<S: Serializer>(size: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    size.map(HeapSize::Bytes).serialize(serializer)
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    match HeapSize::deserialize(deserializer)? {
+        HeapSize::Bytes(bytes) => Ok(Some(bytes)),
+        HeapSize::Multiplier(_) => Err(de::Error::custom(
+            "expected an absolute size such as \"8GiB\"",
+        )),
+    }
+}
+
+/// The size of a heap, either relative to its vanilla size or absolute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeapSize {
+    /// A multiple of the vanilla size, `3` or `1.5`.
+    Multiplier(f64),
+    /// A size in bytes, `"768MiB"`. Sizes smaller than vanilla are ignored.
+    Bytes(u64),
+}
+
+impl HeapSize {
+    const UNITS: [(&'static str, u64); 4] = [
+        ("GiB", 1 << 30),
+        ("MiB", 1 << 20),
+        ("KiB", 1 << 10),
+        ("B", 1),
+    ];
+
+    /// Applies the global `heap_size_multiplier`, which only scales relative sizes.
+    fn normalize(self, heap_size_multiplier: u32) -> Self {
+        match self {
+            Self::Multiplier(multiplier) => {
+                Self::Multiplier(multiplier.max(1.0) * f64::from(heap_size_multiplier))
+            }
+            bytes => bytes,
+        }
+    }
+
+    /// The new size of a heap that is `base` bytes in vanilla.
+    ///
+    /// Never smaller than `base`, and rounded up to 16 bytes if grown by a fractional multiplier.
+    /// Sizes too large for the 32-bit heap size operand are returned as is, to be rejected.
+    pub fn size(self, base: u32) -> u64 {
+        let size = match self {
+            Self::Multiplier(multiplier) => (f64::from(base) * multiplier).ceil() as u64,
+            Self::Bytes(bytes) => bytes,
+        };
+
+        if size <= u64::from(base) {
+            return base.into();
+        }
+
+        size.saturating_add(15) / 16 * 16
+    }
+}
+
+/// Sizes of different kinds cannot be compared without the vanilla size.
+impl PartialOrd for HeapSize {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Self::Multiplier(a), Self::Multiplier(b)) => a.partial_cmp(b),
+            (Self::Bytes(a), Self::Bytes(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HeapSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Multiplier(multiplier) => write!(f, "x{multiplier}"),
+            Self::Bytes(bytes) => {
+                let (unit, scale) = Self::UNITS
+                    .into_iter()
+                    .find(|(_, scale)| bytes % scale == 0)
+                    .unwrap_or(("B", 1));
+
+                write!(f, "{}{unit}", bytes / scale)
+            }
+        }
+    }
+}
+
+impl FromStr for HeapSize {
+    type Err = String;
+
+    /// Parses an absolute size, a number followed by "B", "KiB", "MiB" or "GiB".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid heap size \"{s}\", expected a size such as \"768MiB\"");
+
+        let (number, scale) = Self::UNITS
+            .into_iter()
+            .find_map(|(unit, scale)| Some((s.strip_suffix(unit)?.trim_end(), scale)))
+            .ok_or_else(invalid)?;
+
+        let number = number.parse::<f64>().map_err(|_| invalid())?;
+
+        if !number.is_finite() || number < 0.0 {
+            return Err(invalid());
+        }
+
+        Ok(Self::Bytes((number * scale as f64).round() as u64))
+    }
+}
+
+impl Serialize for HeapSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Self::Multiplier(multiplier) if multiplier.fract() == 0.0 => {
+                serializer.serialize_u64(multiplier as u64)
+            }
+            Self::Multiplier(multiplier) => serializer.serialize_f64(multiplier),
+            Self::Bytes(_) => serializer.collect_str(self),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HeapSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = HeapSize;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a multiplier such as 2 or 1.5, or a size such as \"768MiB\"")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                self.visit_f64(v as f64)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                self.visit_f64(v as f64)
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                if v.is_finite() && v >= 0.0 {
+                    Ok(HeapSize::Multiplier(v))
+                } else {
+                    Err(E::custom(format!("invalid heap size multiplier {v}")))
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
         }
+
+        deserializer.deserialize_any(Visitor)
     }
 }
 
 #[derive(Debug)]
 pub enum ConfigError {
     FileNotFound,
-    IoError,
-    InvalidToml,
+    IoError(io::Error),
+    /// A syntax or type error at a 1-based `line` and `column` of the config.
+    InvalidToml {
+        message: String,
+        line: usize,
+        column: usize,
+        /// The dotted path of the offending key, if the error is about a value.
+        key: Option<String>,
+    },
+    /// A value that is only invalid in combination with a preset.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileNotFound => f.write_str("file not found"),
+            Self::IoError(err) => write!(f, "{err}"),
+            Self::InvalidToml {
+                message,
+                line,
+                column,
+                key,
+            } => {
+                write!(f, "line {line}, column {column}")?;
+
+                if let Some(key) = key {
+                    write!(f, ", `{key}`")?;
+                }
+
+                write!(f, ": {message}")
+            }
+            Self::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+/// A problem in the config that heap_x works around.
+#[derive(Debug)]
+pub enum ConfigWarning {
+    /// The config was written by a newer version of heap_x.
+    NewerVersion(u32),
+    UnknownKey(String),
+    OutOfRange {
+        key: String,
+        value: String,
+        used: String,
+    },
+    /// A heap size does not fit in the 32-bit size operand, nothing is patched.
+    TooLarge {
+        key: String,
+        value: String,
+    },
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NewerVersion(version) => write!(
+                f,
+                "`config_version` = {version} is newer than the supported {CONFIG_VERSION}, \
+                 the keys this version does not know are ignored"
+            ),
+            Self::UnknownKey(key) => write!(f, "unknown key `{key}` is ignored"),
+            Self::OutOfRange { key, value, used } => {
+                write!(f, "`{key}` = {value} is out of range, using {used}")
+            }
+            Self::TooLarge { key, value } => write!(
+                f,
+                "`{key}` = {value} is larger than the 4GiB a heap can be, \
+                 nothing is patched while the heap is enabled"
+            ),
+        }
+    }
 }
 
 impl Config {
-    pub fn read_or_create_default(dll_path: &Path) -> Self {
-        Self::read_or_create(dll_path).normalize()
+    /// Every key a config can set, with the keys of tables as `<table>.<key>`.
+    pub fn keys() -> Vec<String> {
+        let mut keys = [
+            "preset",
+            "patch_character_limit",
+            "patch_soundbank_limit",
+            "patch_morpheme_limit",
+            "patch_map_dtor_stack",
+            "patch_enemy_generator_limit",
+            "dry_run",
+            "allow_unknown_builds",
+            "heap_size_multiplier",
+        ]
+        .map(String::from)
+        .to_vec();
+
+        keys.extend(HeapSizeConfig::NAMES.map(|name| format!("patch_heaps.{name}")));
+
+        keys.extend(HeapSizeConfig::NAMES.map(|name| format!("heap_sizes.{name}")));
+        keys.extend(LimitsConfig::RANGES.map(|(name, ..)| format!("limits.{name}")));
+        keys.extend(["budget.max_total", "budget.exceeded"].map(String::from));
+
+        keys
+    }
+
+    /// Looks up a boolean option by its key, as used by the patch set `toggle`s.
+    pub fn toggle(&self, name: &str) -> Option<bool> {
+        match name {
+            "patch_character_limit" => Some(self.patch_character_limit),
+            "patch_soundbank_limit" => Some(self.patch_soundbank_limit),
+            "patch_morpheme_limit" => Some(self.patch_morpheme_limit),
+            "patch_map_dtor_stack" => Some(self.patch_map_dtor_stack),
+            "patch_enemy_generator_limit" => Some(self.patch_enemy_generator_limit),
+            _ => {
+                let heap = name.strip_prefix("patch_heaps.")?;
+
+                HeapSizeConfig::NAMES
+                    .contains(&heap)
+                    .then(|| self.patch_heaps.get(heap).copied().unwrap_or(true))
+            }
+        }
     }
 
-    fn read(config_path: &Path) -> Result<Self, ConfigError> {
+    fn read_raw(config_path: &Path) -> Result<String, ConfigError> {
         let raw_config = match fs::read_to_string(config_path) {
             Ok(contents) => contents,
             Err(err) => match err.kind() {
                 io::ErrorKind::NotFound => return Err(ConfigError::FileNotFound),
-                _ => return Err(ConfigError::IoError),
+                _ => return Err(ConfigError::IoError(err)),
             },
         };
 
-        match toml::from_str::<Self>(&raw_config) {
-            Ok(config) => Ok(config.normalize()),
-            Err(_) => Err(ConfigError::InvalidToml),
-        }
+        Ok(raw_config)
+    }
+
+    pub fn read(config_path: &Path) -> Result<(Self, Vec<ConfigWarning>), ConfigError> {
+        Self::parse(&Self::read_raw(config_path)?)
     }
 
-    pub fn read_or_create(dll_path: &Path) -> Self {
-        let Some(config_path) = dll_dir_from_path(dll_path).map(|mut path| {
-            path.push("ds2s_heap_x.toml");
-            path
-        }) else {
-            return Self::default();
+    /// Parses and normalizes a config, warning about the unknown keys and out of range values.
+    pub fn parse(raw_config: &str) -> Result<(Self, Vec<ConfigWarning>), ConfigError> {
+        let (config, table, mut warnings) = Self::parse_partial(raw_config)?;
+
+        let config = match config.preset {
+            Some(preset) => {
+                let mut preset_table = preset.table();
+
+                merge(&mut preset_table, without_defaults(table));
+
+                Self::from_table(preset_table)?
+            }
+            None => config,
         };
 
-        use ConfigError::*;
+        warnings.extend(config.check());
+
+        Ok((config.normalize(), warnings))
+    }
+
+    /// Parses a config like `parse`, also returning the keys it sets, without applying its
+    /// preset or normalizing it.
+    pub fn parse_partial(
+        raw_config: &str,
+    ) -> Result<(Self, toml::Table, Vec<ConfigWarning>), ConfigError> {
+        let mut warnings = Vec::new();
+        let mut track = serde_path_to_error::Track::new();
 
-        match Self::read(&config_path) {
-            Ok(config) => config,
-            Err(FileNotFound) | Err(InvalidToml) => {
-                let default = Self::default();
+        let deserializer =
+            serde_path_to_error::Deserializer::new(toml::Deserializer::new(raw_config), &mut track);
 
-                let contents = toml::to_string(&default).expect("valid default toml");
+        let config: Self = serde_ignored::deserialize(deserializer, |path| {
+            warnings.push(ConfigWarning::UnknownKey(path.to_string()))
+        })
+        .map_err(|err: toml::de::Error| {
+            let start = err.span().map_or(0, |span| span.start);
+            let before = &raw_config[..start];
 
-                let _ = fs::write(config_path, contents);
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            let key = track.path().to_string();
 
-                default
+            ConfigError::InvalidToml {
+                message: err.message().trim_end().replace('\n', ", "),
+                line: before.matches('\n').count() + 1,
+                column: before[line_start..].chars().count() + 1,
+                key: (!key.is_empty() && key != ".").then_some(key),
+            }
+        })?;
+
+        if config.config_version > CONFIG_VERSION {
+            warnings.push(ConfigWarning::NewerVersion(config.config_version));
+        }
+
+        for heap in config.patch_heaps.keys() {
+            if !HeapSizeConfig::NAMES.contains(&heap.as_str()) {
+                warnings.push(ConfigWarning::UnknownKey(format!("patch_heaps.{heap}")));
             }
-            Err(IoError) => Self::default(),
         }
+
+        let table = raw_config
+            .parse()
+            .map_err(|err: toml::de::Error| ConfigError::Invalid(err.to_string()))?;
+
+        Ok((config, table, warnings))
     }
 
-    fn normalize(self) -> Self {
+    pub(crate) fn from_table(table: toml::Table) -> Result<Self, ConfigError> {
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|err: toml::de::Error| ConfigError::Invalid(err.message().to_owned()))
+    }
+
+    /// Reads the keys set by the config at `config_path` like `parse_partial`.
+    pub fn read_partial(
+        config_path: &Path,
+    ) -> Result<(Self, toml::Table, Vec<ConfigWarning>), ConfigError> {
+        Self::parse_partial(&Self::read_raw(config_path)?)
+    }
+
+    /// Reads the keys set by the main config at `config_path`, writing the default config
+    /// if it is missing and upgrading it if it was written by an older version.
+    pub fn read_or_create(config_path: &Path, log: &mut Log) -> Option<(Self, toml::Table)> {
+        match Self::read_partial(config_path) {
+            Ok((config, table, warnings)) => {
+                log.write(format_args!("config: read {}", config_path.display()));
+
+                for warning in warnings {
+                    log.write(format_args!("config: warning: {warning}"));
+                }
+
+                if config.config_version < CONFIG_VERSION {
+                    match migration::migrate_file(config_path) {
+                        Ok(()) => log.write(format_args!(
+                            "config: migrated from version {} to {CONFIG_VERSION}",
+                            config.config_version
+                        )),
+                        Err(err) => log.write(format_args!(
+                            "config: failed to migrate from version {}: {err}",
+                            config.config_version
+                        )),
+                    }
+                }
+
+                Some((config, table))
+            }
+            Err(ConfigError::FileNotFound) => {
+                match fs::write(config_path, DEFAULT_CONFIG) {
+                    Ok(()) => log.write(format_args!(
+                        "config: {} not found, wrote default values",
+                        config_path.display()
+                    )),
+                    Err(err) => log.write(format_args!(
+                        "config: {} not found, failed to write default values: {err}",
+                        config_path.display()
+                    )),
+                }
+
+                // Read like the written file would be.
+                Self::parse_partial(DEFAULT_CONFIG)
+                    .ok()
+                    .map(|(config, table, _)| (config, table))
+            }
+            Err(err) => {
+                // The file is left as is, so a typo does not erase the user's settings.
+                log.write(format_args!(
+                    "config: {}: {err}, using default values",
+                    config_path.display()
+                ));
+
+                None
+            }
+        }
+    }
+
+    /// Lists the values `normalize` has to change, and the heap sizes that cannot be patched.
+    pub(crate) fn check(&self) -> Vec<ConfigWarning> {
+        let mut warnings = Vec::new();
+
+        let mut out_of_range = |key: String, value: &dyn fmt::Display, used: &dyn fmt::Display| {
+            warnings.push(ConfigWarning::OutOfRange {
+                key,
+                value: value.to_string(),
+                used: used.to_string(),
+            })
+        };
+
+        if self.heap_size_multiplier == 0 {
+            out_of_range("heap_size_multiplier".to_owned(), &0, &1);
+        }
+
+        for name in HeapSizeConfig::NAMES {
+            if let Some(HeapSize::Multiplier(multiplier)) = self.heap_sizes.get(name) {
+                if multiplier < 1.0 {
+                    out_of_range(format!("heap_sizes.{name}"), &multiplier, &1);
+                }
+            }
+        }
+
+        for (name, min, max) in LimitsConfig::RANGES {
+            if let Some(limit) = self
+                .limits
+                .get(name)
+                .filter(|limit| !(min..=max).contains(limit))
+            {
+                out_of_range(format!("limits.{name}"), &limit, &limit.clamp(min, max));
+            }
+        }
+
+        for name in HeapSizeConfig::NAMES {
+            if let Some(size @ HeapSize::Bytes(bytes)) = self.heap_sizes.get(name) {
+                if bytes > u64::from(u32::MAX) {
+                    warnings.push(ConfigWarning::TooLarge {
+                        key: format!("heap_sizes.{name}"),
+                        value: format!("\"{size}\""),
+                    });
+                }
+            }
+        }
+
+        warnings
+    }
+
+    pub fn normalize(self) -> Self {
         let heap_size_multiplier = self.heap_size_multiplier.max(1);
 
         Self {
             heap_size_multiplier: 1,
             heap_sizes: HeapSizeConfig {
-                debug: self
-                    .heap_sizes
-                    .debug
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                facegen: self
-                    .heap_sizes
-                    .facegen
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                file_data: self
-                    .heap_sizes
-                    .file_data
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                global: self
-                    .heap_sizes
-                    .global
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                graphics: self
-                    .heap_sizes
-                    .graphics
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                gui: self
-                    .heap_sizes
-                    .gui
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                havok: self
-                    .heap_sizes
-                    .havok
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                menu: self
-                    .heap_sizes
-                    .menu
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                morpheme: self
-                    .heap_sizes
-                    .morpheme
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                network: self
-                    .heap_sizes
-                    .network
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                player: self
-                    .heap_sizes
-                    .player
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                regulation: self
-                    .heap_sizes
-                    .regulation
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                scene_graph: self
-                    .heap_sizes
-                    .scene_graph
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                sfx: self
-                    .heap_sizes
-                    .sfx
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                sound: self
-                    .heap_sizes
-                    .sound
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                string_data: self
-                    .heap_sizes
-                    .string_data
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                system: self
-                    .heap_sizes
-                    .system
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                temp: self
-                    .heap_sizes
-                    .temp
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
-                temp2: self
-                    .heap_sizes
-                    .temp2
-                    .max(1)
-                    .saturating_mul(heap_size_multiplier),
+                debug: self.heap_sizes.debug.normalize(heap_size_multiplier),
+                facegen: self.heap_sizes.facegen.normalize(heap_size_multiplier),
+                file_data: self.heap_sizes.file_data.normalize(heap_size_multiplier),
+                global: self.heap_sizes.global.normalize(heap_size_multiplier),
+                graphics: self.heap_sizes.graphics.normalize(heap_size_multiplier),
+                gui: self.heap_sizes.gui.normalize(heap_size_multiplier),
+                havok: self.heap_sizes.havok.normalize(heap_size_multiplier),
+                menu: self.heap_sizes.menu.normalize(heap_size_multiplier),
+                morpheme: self.heap_sizes.morpheme.normalize(heap_size_multiplier),
+                network: self.heap_sizes.network.normalize(heap_size_multiplier),
+                player: self.heap_sizes.player.normalize(heap_size_multiplier),
+                regulation: self.heap_sizes.regulation.normalize(heap_size_multiplier),
+                scene_graph: self.heap_sizes.scene_graph.normalize(heap_size_multiplier),
+                sfx: self.heap_sizes.sfx.normalize(heap_size_multiplier),
+                sound: self.heap_sizes.sound.normalize(heap_size_multiplier),
+                string_data: self.heap_sizes.string_data.normalize(heap_size_multiplier),
+                system: self.heap_sizes.system.normalize(heap_size_multiplier),
+                temp: self.heap_sizes.temp.normalize(heap_size_multiplier),
+                temp2: self.heap_sizes.temp2.normalize(heap_size_multiplier),
             },
+            limits: self.limits.normalize(),
             ..self
         }
     }
 }
 
-fn dll_dir_from_path(dll_path: &Path) -> Option<PathBuf> {
+/// Merges `overrides` into `base`, tables are merged key by key and any other value replaces
+/// the value in `base`.
+pub fn merge(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => {
+                merge(base, overrides)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Removes the keys of a config with a preset that are still at their default values, so the
+/// preset sets them instead.
+pub(crate) fn without_defaults(mut table: toml::Table) -> toml::Table {
+    let defaults = toml::Table::try_from(Config::default()).expect("serializable config");
+
+    strip_defaults(&mut table, &defaults);
+
+    table
+}
+
+fn strip_defaults(table: &mut toml::Table, defaults: &toml::Table) {
+    table.retain(|key, value| match (value, defaults.get(key)) {
+        (_, _) if key == "config_version" => true,
+        (toml::Value::Table(table), Some(toml::Value::Table(defaults))) => {
+            strip_defaults(table, defaults);
+
+            !table.is_empty()
+        }
+        (value, Some(default)) => value != default,
+        (_, None) => true,
+    });
+}
+
+pub(crate) fn dll_dir_from_path(dll_path: &Path) -> Option<PathBuf> {
     let dirname = dll_path.parent()?;
 
     dirname.canonicalize().ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_toml(config: &Config) -> String {
+        toml::to_string(config).unwrap()
+    }
+
+    #[test]
+    fn default_config_has_default_values() {
+        let (config, warnings) = Config::parse(DEFAULT_CONFIG).unwrap();
+
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(to_toml(&config), to_toml(&Config::default().normalize()));
+    }
+
+    #[test]
+    fn multiplier_only_scales_relative_sizes() {
+        let config = Config {
+            heap_size_multiplier: 2,
+            heap_sizes: HeapSizeConfig {
+                sound: HeapSize::Multiplier(1.5),
+                sfx: HeapSize::Bytes(768 << 20),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .normalize();
+
+        assert_eq!(config.heap_size_multiplier, 1);
+        assert_eq!(config.heap_sizes.sound, HeapSize::Multiplier(3.0));
+        assert_eq!(config.heap_sizes.sfx, HeapSize::Bytes(768 << 20));
+    }
+
+    #[test]
+    fn reports_type_error_position() {
+        let err = Config::parse("heap_size_multiplier = 2\n\n[heap_sizes]\nsound = true\n")
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            &err,
+            ConfigError::InvalidToml { line: 4, column: 9, key: Some(key), .. }
+                if key == "heap_sizes.sound"
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("line 4, column 9, `heap_sizes.sound`: "));
+    }
+
+    #[test]
+    fn reports_syntax_error_position() {
+        let err = Config::parse("[limits]\ncharacter = 2048\nsoundbank = = 1\n")
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            err,
+            ConfigError::InvalidToml {
+                line: 3,
+                column: 13,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn warns_about_unknown_keys() {
+        let (_, warnings) =
+            Config::parse("patch_soundbank_limt = false\n[heap_sizes]\nsoud = 2\n").unwrap();
+
+        let warnings = warnings.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        assert_eq!(
+            warnings,
+            [
+                "unknown key `patch_soundbank_limt` is ignored",
+                "unknown key `heap_sizes.soud` is ignored",
+            ]
+        );
+    }
+
+    #[test]
+    fn clamps_out_of_range_values() {
+        let (config, warnings) =
+            Config::parse("heap_size_multiplier = 0\n[limits]\ncharacter = 8192\n").unwrap();
+
+        let warnings = warnings.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        assert_eq!(
+            warnings,
+            [
+                "`heap_size_multiplier` = 0 is out of range, using 1",
+                "`limits.character` = 8192 is out of range, using 4096",
+            ]
+        );
+        assert_eq!(config.limits.character, 4096);
+        assert_eq!(config.heap_sizes.sound, HeapSize::Multiplier(3.0));
+    }
+
+    #[test]
+    fn warns_about_heaps_over_4_gib() {
+        let (config, warnings) = Config::parse("[heap_sizes]\nsound = \"8GiB\"\n").unwrap();
+
+        let warnings = warnings.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        assert_eq!(
+            warnings,
+            [
+                "`heap_sizes.sound` = \"8GiB\" is larger than the 4GiB a heap can be, \
+              nothing is patched while the heap is enabled"
+            ]
+        );
+        // Kept as is, to be rejected when patching.
+        assert_eq!(config.heap_sizes.sound, HeapSize::Bytes(8 << 30));
+    }
+
+    #[test]
+    fn preset_fills_unset_keys() {
+        let (config, _) = Config::parse("preset = \"vanilla\"\n[heap_sizes]\nsound = 2\n").unwrap();
+
+        assert_eq!(config.preset, Some(Preset::Vanilla));
+        assert_eq!(config.heap_sizes.sound, HeapSize::Multiplier(2.0));
+        assert_eq!(config.heap_sizes.havok, HeapSize::Multiplier(1.0));
+        assert_eq!(config.limits.soundbank, 48);
+    }
+
+    #[test]
+    fn preset_sets_keys_left_at_default_values() {
+        let template = DEFAULT_CONFIG.replace("# preset = ", "preset = ");
+        let (config, _) = Config::parse(&template).unwrap();
+        let (preset, _) = Config::parse(&format!(
+            "config_version = {CONFIG_VERSION}\npreset = \"large_mod\"\n"
+        ))
+        .unwrap();
+
+        assert_eq!(to_toml(&config), to_toml(&preset));
+
+        let (config, _) = Config::parse(&template.replace("sound = 3", "sound = 5")).unwrap();
+
+        // Scaled by the `heap_size_multiplier` of the preset.
+        assert_eq!(config.heap_sizes.sound, HeapSize::Multiplier(10.0));
+        assert_eq!(
+            toml::to_string(&config.limits).unwrap(),
+            toml::to_string(&preset.limits).unwrap()
+        );
+    }
+
+    #[test]
+    fn parses_heap_sizes() {
+        assert_eq!("768MiB".parse(), Ok(HeapSize::Bytes(768 << 20)));
+        assert_eq!("1.5 GiB".parse(), Ok(HeapSize::Bytes(3 << 29)));
+        assert!("768".parse::<HeapSize>().is_err());
+        assert!("-1MiB".parse::<HeapSize>().is_err());
+
+        assert_eq!(HeapSize::Bytes(768 << 20).to_string(), "768MiB");
+        assert_eq!(HeapSize::Bytes(1000).to_string(), "1000B");
+    }
+
+    #[test]
+    fn sizes_never_shrink_and_round_up() {
+        assert_eq!(HeapSize::Multiplier(0.5).size(0x1000), 0x1000);
+        assert_eq!(HeapSize::Bytes(16).size(0x1000), 0x1000);
+        assert_eq!(HeapSize::Multiplier(1.001).size(0x1000), 0x1010);
+        assert_eq!(HeapSize::Bytes(0x2001).size(0x1000), 0x2010);
+    }
+}
@@ -0,0 +1,514 @@
+use std::{collections::HashMap, fmt, fs, io, path::Path};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    builds::{self, BuildStatus, Version},
+    config::{dll_dir_from_path, Config, HeapSizeConfig, LimitsConfig},
+    expr::{Expr, ExprError},
+    patches::Site,
+    scan::Pattern,
+};
+
+/// A declarative description of every patch site, loaded from a TOML patch file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatchSet {
+    /// The game version the patch set was written for.
+    pub version: Version,
+    /// The lowercase hex SHA-256 hashes of the executables the patch set was validated against.
+    #[serde(default)]
+    pub sha256: Vec<String>,
+    #[serde(default, rename = "group")]
+    pub groups: Vec<GroupDef>,
+    #[serde(default, deserialize_with = "deserialize_constants")]
+    pub constants: HashMap<String, Expr>,
+    #[serde(default, rename = "patch")]
+    pub patches: Vec<PatchDef>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupDef {
+    pub name: String,
+    pub description: Option<String>,
+    /// A boolean `Config` option the group is enabled by.
+    pub toggle: Option<String>,
+    /// The groups that must be applied for this group to be applied.
+    #[serde(default)]
+    pub requires: Vec<String>,
+}
+
+/// Whether a group is applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GroupState {
+    Enabled,
+    /// Disabled by the config option.
+    Disabled(String),
+    /// A required group is not applied.
+    MissingRequirement(String),
+}
+
+impl fmt::Display for GroupState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Enabled => f.write_str("enabled"),
+            Self::Disabled(toggle) => write!(f, "disabled by `{toggle}`"),
+            Self::MissingRequirement(group) => write!(f, "requires {group}, which is not applied"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatchDef {
+    pub group: String,
+    pub name: String,
+    pub rva: Option<usize>,
+    pub signature: Option<Pattern>,
+    #[serde(default)]
+    pub operand: usize,
+    /// The PE section the site must lie in.
+    #[serde(default = "default_section")]
+    pub section: String,
+    pub op: PatchOp,
+    pub expected: Option<Expr>,
+    pub expected_bytes: Option<Pattern>,
+    /// Why the site has no expected original value, required without one.
+    pub unchecked: Option<String>,
+    pub value: Option<Expr>,
+    /// For "heap", the `HeapSizeConfig` key of the heap.
+    pub heap: Option<String>,
+    pub len: Option<usize>,
+    #[serde(default)]
+    pub global_heap: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchOp {
+    Set,
+    Add,
+    Mul,
+    Heap,
+    Nop,
+    GlobalHeap,
+}
+
+#[derive(Debug)]
+pub enum PatchSetError {
+    IoError(io::Error),
+    InvalidToml(toml::de::Error),
+    /// There is no embedded patch set for the game version.
+    UnsupportedVersion(Version),
+    /// The patch set file was written for another game version.
+    VersionMismatch {
+        game: Version,
+        patch_set: Version,
+    },
+    /// The executable has the patch set version, but is none of the builds it was validated against,
+    /// and the config does not allow unknown builds.
+    UnknownHash {
+        version: Version,
+        sha256: String,
+    },
+    /// A patch or group definition is inconsistent.
+    Invalid {
+        name: String,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for PatchSetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IoError(err) => write!(f, "{err}"),
+            Self::InvalidToml(err) => write!(f, "{err}"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "DarkSoulsII.exe {version}: {}, there is no patch set for it",
+                BuildStatus::Unknown
+            ),
+            Self::VersionMismatch { game, patch_set } => write!(
+                f,
+                "the patch set is for DarkSoulsII.exe {patch_set}, found {game}"
+            ),
+            Self::UnknownHash { version, sha256 } => write!(
+                f,
+                "DarkSoulsII.exe {version}: {}, SHA-256 {sha256}, set `allow_unknown_builds = true` \
+                 to patch it anyway",
+                BuildStatus::VersionMatchUnknownHash
+            ),
+            Self::Invalid { name, reason } => write!(f, "\"{name}\": {reason}"),
+        }
+    }
+}
+
+impl PatchSet {
+    /// The embedded patch set for `version`, see `builds::BUILDS`.
+    pub fn embedded(version: &Version) -> Result<Self, PatchSetError> {
+        let build = builds::find(version).ok_or(PatchSetError::UnsupportedVersion(*version))?;
+
+        Ok(build.patch_set.parse().expect("valid embedded patch set"))
+    }
+
+    /// Reads "ds2s_heap_x.patches.toml" next to the DLL if it exists,
+    /// otherwise uses the embedded patch set for `version`.
+    ///
+    /// The patch set file may target a version without an embedded patch set,
+    /// but it must match the game version.
+    pub fn read_or_embedded(dll_path: &Path, version: &Version) -> Result<Self, PatchSetError> {
+        let Some(patch_set_path) = dll_dir_from_path(dll_path).map(|mut path| {
+            path.push("ds2s_heap_x.patches.toml");
+            path
+        }) else {
+            return Self::embedded(version);
+        };
+
+        match Self::read(&patch_set_path) {
+            Ok(patch_set) => patch_set.check_version(version),
+            Err(PatchSetError::IoError(err)) if err.kind() == io::ErrorKind::NotFound => {
+                Self::embedded(version)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn check_version(self, version: &Version) -> Result<Self, PatchSetError> {
+        if self.version == *version {
+            Ok(self)
+        } else {
+            Err(PatchSetError::VersionMismatch {
+                game: *version,
+                patch_set: self.version,
+            })
+        }
+    }
+
+    pub fn read(patch_set_path: &Path) -> Result<Self, PatchSetError> {
+        fs::read_to_string(patch_set_path)
+            .map_err(PatchSetError::IoError)?
+            .parse()
+    }
+
+    pub fn group(&self, name: &str) -> Option<&GroupDef> {
+        self.groups.iter().find(|group| group.name == name)
+    }
+
+    pub fn group_enabled(&self, name: &str, config: &Config) -> bool {
+        self.group_state(name, config) == GroupState::Enabled
+    }
+
+    /// Whether a group is applied, as enabled by its toggle and its required groups.
+    pub fn group_state(&self, name: &str, config: &Config) -> GroupState {
+        let Some(group) = self.group(name) else {
+            return GroupState::Enabled;
+        };
+
+        if let Some(toggle) = &group.toggle {
+            if config.toggle(toggle) == Some(false) {
+                return GroupState::Disabled(toggle.clone());
+            }
+        }
+
+        // Requirements are checked to be acyclic by `validate`.
+        for required in &group.requires {
+            if !self.group_enabled(required, config) {
+                return GroupState::MissingRequirement(required.clone());
+            }
+        }
+
+        GroupState::Enabled
+    }
+
+    /// Follows the requirements of `group`, returning the first group that requires itself.
+    fn requirement_cycle<'a>(&'a self, group: &'a str, path: &mut Vec<&'a str>) -> Option<&'a str> {
+        if path.contains(&group) {
+            return Some(group);
+        }
+
+        path.push(group);
+
+        let cycle = self.group(group).and_then(|def| {
+            def.requires
+                .iter()
+                .find_map(|required| self.requirement_cycle(required, path))
+        });
+
+        path.pop();
+
+        cycle
+    }
+
+    /// Evaluates `expr`, looking up variables with `lookup` first and then in the patch set constants,
+    /// which can provide defaults for the variables `lookup` leaves unset.
+    pub fn eval(
+        &self,
+        expr: &Expr,
+        lookup: &dyn Fn(&str) -> Option<u64>,
+    ) -> Result<u64, ExprError> {
+        self.eval_with(expr, lookup, &mut Vec::new())
+    }
+
+    fn eval_with<'a>(
+        &'a self,
+        expr: &Expr,
+        lookup: &dyn Fn(&str) -> Option<u64>,
+        evaluating: &mut Vec<&'a str>,
+    ) -> Result<u64, ExprError> {
+        expr.eval(&mut |name| {
+            if let Some(val) = lookup(name) {
+                return Ok(val);
+            }
+
+            let (name, constant) = self
+                .constants
+                .get_key_value(name)
+                .ok_or_else(|| ExprError::UnknownVariable(name.to_owned()))?;
+
+            if evaluating.contains(&name.as_str()) {
+                return Err(ExprError::Cycle(name.clone()));
+            }
+
+            evaluating.push(name);
+
+            let val = self.eval_with(constant, lookup, evaluating);
+
+            evaluating.pop();
+
+            // Limits derived by the patch set are held to the same range as configured ones.
+            let range = name.strip_prefix("limits.").and_then(|limit| {
+                LimitsConfig::RANGES
+                    .into_iter()
+                    .find(|(key, ..)| *key == limit)
+            });
+
+            match range {
+                Some((_, min, max)) => val.map(|val| val.clamp(min.into(), max.into())),
+                None => val,
+            }
+        })
+    }
+
+    fn validate(&self) -> Result<(), PatchSetError> {
+        let default_config = Config::default();
+
+        for sha256 in &self.sha256 {
+            if sha256.len() != 64
+                || !sha256
+                    .bytes()
+                    .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+            {
+                return Err(PatchSetError::Invalid {
+                    name: sha256.clone(),
+                    reason: "`sha256` must be 64 lowercase hex digits",
+                });
+            }
+        }
+
+        for group in &self.groups {
+            if group
+                .toggle
+                .as_deref()
+                .is_some_and(|toggle| default_config.toggle(toggle).is_none())
+            {
+                return Err(PatchSetError::Invalid {
+                    name: group.name.clone(),
+                    reason: "unknown toggle",
+                });
+            }
+
+            if group
+                .requires
+                .iter()
+                .any(|required| self.group(required).is_none())
+            {
+                return Err(PatchSetError::Invalid {
+                    name: group.name.clone(),
+                    reason: "requires an undeclared group",
+                });
+            }
+
+            if let Some(group) = self.requirement_cycle(&group.name, &mut Vec::new()) {
+                return Err(PatchSetError::Invalid {
+                    name: group.to_owned(),
+                    reason: "requires itself",
+                });
+            }
+        }
+
+        for patch in &self.patches {
+            let invalid = |reason| {
+                Err(PatchSetError::Invalid {
+                    name: patch.name.clone(),
+                    reason,
+                })
+            };
+
+            if self.group(&patch.group).is_none() {
+                return invalid("undeclared group");
+            }
+
+            if patch.rva.is_some() == patch.signature.is_some() {
+                return invalid("exactly one of `rva` or `signature` is required");
+            }
+
+            match patch.op {
+                PatchOp::Set | PatchOp::Add | PatchOp::Mul if patch.value.is_none() => {
+                    return invalid("`value` is required");
+                }
+                PatchOp::Heap if patch.value.is_some() => {
+                    return invalid("\"heap\" takes a `heap` instead of a `value`");
+                }
+                PatchOp::Heap
+                    if !patch
+                        .heap
+                        .as_deref()
+                        .is_some_and(|heap| HeapSizeConfig::NAMES.contains(&heap)) =>
+                {
+                    return invalid("`heap` must be a heap size key of the config");
+                }
+                PatchOp::Nop if patch.len.is_none() => return invalid("`len` is required"),
+                PatchOp::Nop if patch.expected.is_some() => {
+                    return invalid("use `expected_bytes` with \"nop\"");
+                }
+                _ => {}
+            }
+
+            match (
+                patch.expected.is_some() || patch.expected_bytes.is_some(),
+                patch.unchecked.is_some(),
+            ) {
+                (false, false) => {
+                    return invalid(
+                        "`expected` or `expected_bytes` is required, or `unchecked` with the reason",
+                    );
+                }
+                (true, true) => return invalid("`unchecked` with an expected original value"),
+                _ => {}
+            }
+
+            if patch.heap.is_some() && patch.op != PatchOp::Heap {
+                return invalid("`heap` is only valid with \"heap\"");
+            }
+
+            if patch.global_heap && !matches!(patch.op, PatchOp::Mul | PatchOp::Heap) {
+                return invalid("`global_heap` is only valid with \"mul\" and \"heap\"");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for PatchSet {
+    type Err = PatchSetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let patch_set = toml::from_str::<Self>(s).map_err(PatchSetError::InvalidToml)?;
+
+        patch_set.validate()?;
+
+        Ok(patch_set)
+    }
+}
+
+impl PatchDef {
+    pub fn site(&self) -> Site {
+        match (&self.rva, &self.signature) {
+            (_, Some(pattern)) => Site::Signature {
+                pattern: pattern.clone(),
+                operand: self.operand,
+            },
+            (rva, None) => Site::Rva(rva.unwrap_or_default() + self.operand),
+        }
+    }
+}
+
+fn default_section() -> String {
+    ".text".to_owned()
+}
+
+/// Flattens nested constant tables into dotted names, `[constants.chr] old_capacity = 32`
+/// becomes `chr.old_capacity`.
+fn deserialize_constants<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Expr>, D::Error> {
+    fn flatten<E: serde::de::Error>(
+        prefix: &str,
+        table: toml::Table,
+        constants: &mut HashMap<String, Expr>,
+    ) -> Result<(), E> {
+        for (key, value) in table {
+            let name = if prefix.is_empty() {
+                key
+            } else {
+                format!("{prefix}.{key}")
+            };
+
+            match value {
+                toml::Value::Table(table) => flatten(&name, table, constants)?,
+                value => {
+                    let expr = Expr::deserialize(value).map_err(E::custom)?;
+
+                    constants.insert(name, expr);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut constants = HashMap::new();
+
+    flatten("", toml::Table::deserialize(deserializer)?, &mut constants)?;
+
+    Ok(constants)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch_set(site: &str) -> Result<PatchSet, PatchSetError> {
+        format!(
+            "version = \"1.0.3.0\"\n[[group]]\nname = \"group\"\n\
+             [[patch]]\ngroup = \"group\"\nname = \"site\"\nrva = 0x1000\nop = \"set\"\n\
+             value = 1\n{site}"
+        )
+        .parse()
+    }
+
+    #[test]
+    fn clamps_derived_limits() {
+        let patch_set =
+            patch_set("expected = 0\n[constants.limits]\nmorpheme = \"0x3000 * heap\"\n").unwrap();
+
+        let eval = |heap: u64| {
+            let lookup = |name: &str| (name == "heap").then_some(heap);
+
+            patch_set.eval(&"limits.morpheme".parse().unwrap(), &lookup)
+        };
+
+        assert_eq!(eval(4).unwrap(), 0x3000 * 4);
+        assert_eq!(eval(32).unwrap(), 0x30000);
+        assert_eq!(eval(0).unwrap(), 0x3000);
+    }
+
+    #[test]
+    fn requires_expected_value_or_reason() {
+        assert!(patch_set("expected = 0\n").is_ok());
+        assert!(patch_set("expected_bytes = \"00 ??\"\n").is_ok());
+        assert!(patch_set("unchecked = \"not recorded\"\n").is_ok());
+
+        for site in ["", "expected = 0\nunchecked = \"not recorded\"\n"] {
+            assert!(
+                matches!(
+                    patch_set(site),
+                    Err(PatchSetError::Invalid { name, .. }) if name == "site"
+                ),
+                "{site:?}"
+            );
+        }
+    }
+}
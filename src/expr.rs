@@ -0,0 +1,332 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer};
+
+/// An integer expression used for patch values, e.g. `"chr.element_size * chr.new_capacity + 16"`.
+///
+/// Supports decimal and `0x` hex literals, `+`, `-`, `*`, `/`, parentheses and variables.
+/// Variable names may contain dots to refer to namespaced values, such as `heap.sound`.
+/// Evaluation is done on `u64` and any overflow, underflow or division by zero is an error.
+///
+#[derive(Clone, Debug)]
+pub struct Expr {
+    source: String,
+    node: Node,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Literal(u64),
+    Variable(String),
+    Binary(Op, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug)]
+pub enum ExprError {
+    Syntax {
+        source: String,
+        position: usize,
+    },
+    UnknownVariable(String),
+    /// A constant that (indirectly) refers to itself.
+    Cycle(String),
+    Overflow(String),
+    DivisionByZero(String),
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax { source, position } => {
+                write!(f, "syntax error in \"{source}\" at position {position}")
+            }
+            Self::UnknownVariable(name) => write!(f, "unknown variable \"{name}\""),
+            Self::Cycle(name) => write!(f, "\"{name}\" refers to itself"),
+            Self::Overflow(source) => write!(f, "\"{source}\" overflows"),
+            Self::DivisionByZero(source) => write!(f, "\"{source}\" divides by zero"),
+        }
+    }
+}
+
+impl Expr {
+    pub fn literal(val: u64) -> Self {
+        Self {
+            source: val.to_string(),
+            node: Node::Literal(val),
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Evaluates the expression, looking up variables with `lookup`.
+    pub fn eval(
+        &self,
+        lookup: &mut dyn FnMut(&str) -> Result<u64, ExprError>,
+    ) -> Result<u64, ExprError> {
+        self.node.eval(&self.source, lookup)
+    }
+}
+
+impl Node {
+    fn eval(
+        &self,
+        source: &str,
+        lookup: &mut dyn FnMut(&str) -> Result<u64, ExprError>,
+    ) -> Result<u64, ExprError> {
+        match self {
+            Self::Literal(val) => Ok(*val),
+            Self::Variable(name) => lookup(name),
+            Self::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(source, lookup)?;
+                let rhs = rhs.eval(source, lookup)?;
+
+                match op {
+                    Op::Add => lhs.checked_add(rhs),
+                    Op::Sub => lhs.checked_sub(rhs),
+                    Op::Mul => lhs.checked_mul(rhs),
+                    Op::Div if rhs == 0 => {
+                        return Err(ExprError::DivisionByZero(source.to_owned()))
+                    }
+                    Op::Div => lhs.checked_div(rhs),
+                }
+                .ok_or_else(|| ExprError::Overflow(source.to_owned()))
+            }
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = ExprError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { source: s, pos: 0 };
+
+        let node = parser.expr()?;
+
+        parser.skip_whitespace();
+
+        if parser.pos != s.len() {
+            return Err(parser.error());
+        }
+
+        Ok(Self {
+            source: s.to_owned(),
+            node,
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Literal(u64),
+            Expr(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Literal(val) => Ok(Self::literal(val)),
+            Raw::Expr(source) => source.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Recursive descent parser:
+///
+/// expr   = term (("+" | "-") term)*
+/// term   = factor (("*" | "/") factor)*
+/// factor = literal | variable | "(" expr ")"
+///
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn expr(&mut self) -> Result<Node, ExprError> {
+        let mut lhs = self.term()?;
+
+        while let Some(op) = self.op(&[('+', Op::Add), ('-', Op::Sub)]) {
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(self.term()?));
+        }
+
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> Result<Node, ExprError> {
+        let mut lhs = self.factor()?;
+
+        while let Some(op) = self.op(&[('*', Op::Mul), ('/', Op::Div)]) {
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(self.factor()?));
+        }
+
+        Ok(lhs)
+    }
+
+    fn factor(&mut self) -> Result<Node, ExprError> {
+        self.skip_whitespace();
+
+        let rest = &self.source[self.pos..];
+
+        if rest.starts_with('(') {
+            self.pos += 1;
+
+            let node = self.expr()?;
+
+            self.skip_whitespace();
+
+            if !self.source[self.pos..].starts_with(')') {
+                return Err(self.error());
+            }
+
+            self.pos += 1;
+
+            return Ok(node);
+        }
+
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+
+        let token = &rest[..len];
+
+        let node = if token.starts_with(|c: char| c.is_ascii_digit()) {
+            let val = match token.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+                None => token.replace('_', "").parse(),
+            };
+
+            Node::Literal(val.map_err(|_| self.error())?)
+        } else if !token.is_empty() {
+            Node::Variable(token.to_owned())
+        } else {
+            return Err(self.error());
+        };
+
+        self.pos += len;
+
+        Ok(node)
+    }
+
+    fn op(&mut self, ops: &[(char, Op)]) -> Option<Op> {
+        self.skip_whitespace();
+
+        let next = self.source[self.pos..].chars().next()?;
+
+        let (_, op) = ops.iter().find(|(c, _)| *c == next)?;
+
+        self.pos += 1;
+
+        Some(*op)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.source[self.pos..];
+
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn error(&self) -> ExprError {
+        ExprError::Syntax {
+            source: self.source.to_owned(),
+            position: self.pos,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> Result<u64, ExprError> {
+        source.parse::<Expr>()?.eval(&mut |name| match name {
+            "chr.old.vector_size" => Ok(0x108),
+            "limits.character" => Ok(1024),
+            _ => Err(ExprError::UnknownVariable(name.to_owned())),
+        })
+    }
+
+    #[test]
+    fn evaluates_with_precedence() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), 9);
+        assert_eq!(eval("10 - 4 - 3").unwrap(), 3);
+        assert_eq!(eval("100 / 10 / 5").unwrap(), 2);
+        assert_eq!(eval("  ( 2 )*(3+4 ) ").unwrap(), 14);
+    }
+
+    #[test]
+    fn parses_literals() {
+        assert_eq!(eval("0x1_0000").unwrap(), 0x10000);
+        assert_eq!(eval("1_000").unwrap(), 1000);
+        assert_eq!(Expr::literal(16).to_string(), "16");
+    }
+
+    #[test]
+    fn looks_up_variables() {
+        assert_eq!(eval("8 + chr.old.vector_size * 3").unwrap(), 0x320);
+        assert_eq!(eval("limits.character * 52").unwrap(), 53248);
+        assert!(matches!(
+            eval("heap.sound"),
+            Err(ExprError::UnknownVariable(name)) if name == "heap.sound"
+        ));
+    }
+
+    #[test]
+    fn reports_syntax_position() {
+        for (source, expected) in [("1 +", 3), ("(1 + 2", 6), ("1 2", 2), ("0xZZ", 0), ("", 0)] {
+            assert!(
+                matches!(
+                    source.parse::<Expr>(),
+                    Err(ExprError::Syntax { position, .. }) if position == expected
+                ),
+                "{source:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_overflow_and_division_by_zero() {
+        assert!(matches!(eval("1 - 2"), Err(ExprError::Overflow(_))));
+        assert!(matches!(
+            eval("0xFFFF_FFFF_FFFF_FFFF + 1"),
+            Err(ExprError::Overflow(_))
+        ));
+        assert!(matches!(
+            eval("1 / (2 - 2)"),
+            Err(ExprError::DivisionByZero(_))
+        ));
+    }
+
+    #[test]
+    fn deserializes_integers_and_strings() {
+        #[derive(Deserialize)]
+        struct Values {
+            literal: Expr,
+            expr: Expr,
+        }
+
+        let values: Values = toml::from_str("literal = 16\nexpr = \"literal * 2\"").unwrap();
+
+        assert_eq!(values.literal.eval(&mut |_| Ok(0)).unwrap(), 16);
+        assert_eq!(values.expr.eval(&mut |_| Ok(16)).unwrap(), 32);
+        assert!(toml::from_str::<Values>("literal = 1\nexpr = \"1 +\"").is_err());
+    }
+}
@@ -0,0 +1,467 @@
+use std::{cmp::Ordering, collections::BTreeMap, fs, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    config::{dll_dir_from_path, without_defaults, Config, ConfigError, HeapSize},
+    log::Log,
+    overrides::{self, Override},
+    presets::Preset,
+};
+
+/// The main config, written with the default values if it is missing.
+pub const BASE_CONFIG: &str = "ds2s_heap_x.toml";
+/// A directory of config fragments, one per mod, read in file name order.
+pub const FRAGMENTS_DIR: &str = "ds2s_heap_x.d";
+/// The player's overrides, read last.
+pub const USER_CONFIG: &str = "ds2s_heap_x.user.toml";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayerKind {
+    Base,
+    /// Can only raise the values set by the layers before it.
+    Fragment,
+    /// Replaces the values set by the layers before it, also used for the overrides set by
+    /// the environment and command line.
+    User,
+}
+
+/// A config value and the layer that set it.
+pub struct Entry {
+    pub value: toml::Value,
+    pub layer: String,
+}
+
+/// The config keys set by every layer, merged in order:
+///
+/// - the base config sets values,
+/// - fragments set the values that are not set yet and raise the others, taking the larger
+///   heap sizes, limits and `heap_size_multiplier`, and enabling toggles,
+/// - the user config replaces values.
+///
+/// Keys left unset by every layer use their default values.
+pub struct Layers {
+    pub entries: BTreeMap<String, Entry>,
+    /// Messages about values the fragments could not merge.
+    pub warnings: Vec<String>,
+    defaults: toml::Table,
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            warnings: Vec::new(),
+            defaults: toml::Table::try_from(Config::default()).expect("serializable config"),
+        }
+    }
+}
+
+impl Layers {
+    /// Merges the keys set by a config file, after the keys of its preset. With a preset, the
+    /// keys still at their default values are left to the preset.
+    pub fn add(
+        &mut self,
+        kind: LayerKind,
+        layer: &str,
+        preset: Option<Preset>,
+        table: toml::Table,
+    ) {
+        let table = match preset {
+            Some(preset) => {
+                self.add_table(kind, &format!("{layer} (preset {preset})"), preset.table());
+
+                without_defaults(table)
+            }
+            None => table,
+        };
+
+        self.add_table(kind, layer, table);
+    }
+
+    fn add_table(&mut self, kind: LayerKind, layer: &str, table: toml::Table) {
+        for (key, value) in table {
+            match (key.as_str(), value) {
+                // Only describe the file they are in.
+                ("config_version" | "preset", _) => {}
+                (_, toml::Value::Table(table)) => {
+                    for (name, value) in table {
+                        self.set(kind, layer, format!("{key}.{name}"), value);
+                    }
+                }
+                (_, value) => self.set(kind, layer, key, value),
+            }
+        }
+    }
+
+    fn set(&mut self, kind: LayerKind, layer: &str, key: String, value: toml::Value) {
+        // Unknown keys are reported when the file is read.
+        if !Config::keys().contains(&key) {
+            return;
+        }
+
+        if kind == LayerKind::Fragment {
+            match self.entries.get(&key) {
+                Some(current) => match compare(&key, &value, &current.value) {
+                    Some(Ordering::Greater) => {}
+                    Some(_) => return,
+                    None => self.warnings.push(format!(
+                        "`{key}` = {value} from {layer} cannot be compared with {} from {}, \
+                         using {value}",
+                        current.value, current.layer
+                    )),
+                },
+                // Only the values set by a layer are worth a warning.
+                None => {
+                    if let Some(Ordering::Less | Ordering::Equal) = self
+                        .default_value(&key)
+                        .and_then(|default| compare(&key, &value, default))
+                    {
+                        return;
+                    }
+                }
+            }
+        }
+
+        let layer = layer.to_owned();
+
+        self.entries.insert(key, Entry { value, layer });
+    }
+
+    fn default_value(&self, key: &str) -> Option<&toml::Value> {
+        match key.split_once('.') {
+            Some((section, name)) => self.defaults.get(section)?.get(name),
+            None => self.defaults.get(key),
+        }
+    }
+
+    /// The merged config, not normalized yet.
+    pub fn to_config(&self) -> Result<Config, ConfigError> {
+        let mut table = toml::Table::new();
+
+        for (key, entry) in &self.entries {
+            match key.split_once('.') {
+                Some((section, name)) => {
+                    if let toml::Value::Table(section) = table
+                        .entry(section)
+                        .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                    {
+                        section.insert(name.to_owned(), entry.value.clone());
+                    }
+                }
+                None => {
+                    table.insert(key.clone(), entry.value.clone());
+                }
+            }
+        }
+
+        Config::from_table(table)
+    }
+}
+
+/// Compares two values of `key`, `None` if they cannot be compared, like a relative and an
+/// absolute heap size.
+fn compare(key: &str, a: &toml::Value, b: &toml::Value) -> Option<Ordering> {
+    if key.starts_with("heap_sizes.") || key == "budget.max_total" {
+        let a = HeapSize::deserialize(a.clone()).ok()?;
+        let b = HeapSize::deserialize(b.clone()).ok()?;
+
+        return a.partial_cmp(&b);
+    }
+
+    match (a, b) {
+        (toml::Value::Integer(a), toml::Value::Integer(b)) => Some(a.cmp(b)),
+        (toml::Value::Boolean(a), toml::Value::Boolean(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Reads every config layer next to the DLL, logging which layer set each value.
+pub fn read(dll_path: &Path, log: &mut Log) -> Config {
+    let Some(dir) = dll_dir_from_path(dll_path) else {
+        log.write("config: DLL directory not found, using default values");
+
+        return Config::default().normalize();
+    };
+
+    let base = Config::read_or_create(&dir.join(BASE_CONFIG), log);
+
+    read_layers(&dir, BASE_CONFIG, base, log)
+}
+
+/// Merges the base config already read as `base_layer` with the fragments and user config
+/// in `dir` and the overrides, logging which layer set each value.
+pub fn read_layers(
+    dir: &Path,
+    base_layer: &str,
+    base: Option<(Config, toml::Table)>,
+    log: &mut Log,
+) -> Config {
+    let mut layers = Layers::default();
+
+    if let Some((config, table)) = base {
+        layers.add(LayerKind::Base, base_layer, config.preset, table);
+    }
+
+    let mut fragments = fs::read_dir(dir.join(FRAGMENTS_DIR))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "toml")
+        })
+        .collect::<Vec<_>>();
+
+    fragments.sort();
+
+    for path in fragments {
+        let Some(file_name) = path.file_name() else {
+            continue;
+        };
+
+        let layer = format!("{FRAGMENTS_DIR}/{}", file_name.to_string_lossy());
+
+        read_layer(&mut layers, LayerKind::Fragment, &layer, &path, log);
+    }
+
+    read_layer(
+        &mut layers,
+        LayerKind::User,
+        USER_CONFIG,
+        &dir.join(USER_CONFIG),
+        log,
+    );
+
+    // Applied over every file, before `normalize` like the files.
+    for result in overrides::read() {
+        match result {
+            Ok(Override {
+                source,
+                table,
+                preset,
+            }) => {
+                log.write(format_args!("config: override {source}"));
+
+                layers.add(LayerKind::User, &source, preset, table);
+            }
+            Err(err) => log.write(format_args!("config: override {err}, ignored")),
+        }
+    }
+
+    for warning in &layers.warnings {
+        log.write(format_args!("config: warning: {warning}"));
+    }
+
+    let config = match layers.to_config() {
+        Ok(config) => config,
+        Err(err) => {
+            log.write(format_args!("config: {err}, using default values"));
+
+            return Config::default().normalize();
+        }
+    };
+
+    for warning in config.check() {
+        log.write(format_args!("config: warning: {warning}"));
+    }
+
+    for (key, entry) in &layers.entries {
+        log.write(format_args!(
+            "config: `{key}` = {} from {}",
+            entry.value, entry.layer
+        ));
+    }
+
+    config.normalize()
+}
+
+fn read_layer(layers: &mut Layers, kind: LayerKind, layer: &str, path: &Path, log: &mut Log) {
+    match Config::read_partial(path) {
+        Ok((config, table, warnings)) => {
+            log.write(format_args!("config: read {}", path.display()));
+
+            for warning in warnings {
+                log.write(format_args!("config: warning: {layer}: {warning}"));
+            }
+
+            layers.add(kind, layer, config.preset, table);
+        }
+        Err(ConfigError::FileNotFound) => {}
+        Err(err) => log.write(format_args!("config: {}: {err}, skipped", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(layers: &mut Layers, kind: LayerKind, name: &str, source: &str) {
+        let (config, table, _) = Config::parse_partial(source).unwrap();
+
+        layers.add(kind, name, config.preset, table);
+    }
+
+    fn value<'a>(layers: &'a Layers, key: &str) -> (String, &'a str) {
+        let entry = &layers.entries[key];
+
+        (entry.value.to_string(), entry.layer.as_str())
+    }
+
+    #[test]
+    fn fragments_only_raise_values() {
+        let mut layers = Layers::default();
+
+        layer(
+            &mut layers,
+            LayerKind::Base,
+            BASE_CONFIG,
+            "patch_soundbank_limit = false\n[heap_sizes]\nsound = 4\nsfx = 8\n\
+             [limits]\ncharacter = 2048\n",
+        );
+        layer(
+            &mut layers,
+            LayerKind::Fragment,
+            "ds2s_heap_x.d/a.toml",
+            "patch_soundbank_limit = true\n[heap_sizes]\nsound = 6\nsfx = 2\n\
+             [limits]\ncharacter = 512\n",
+        );
+
+        assert_eq!(
+            value(&layers, "patch_soundbank_limit"),
+            ("true".to_owned(), "ds2s_heap_x.d/a.toml")
+        );
+        assert_eq!(
+            value(&layers, "heap_sizes.sound"),
+            ("6".to_owned(), "ds2s_heap_x.d/a.toml")
+        );
+        assert_eq!(
+            value(&layers, "heap_sizes.sfx"),
+            ("8".to_owned(), BASE_CONFIG)
+        );
+        assert_eq!(
+            value(&layers, "limits.character"),
+            ("2048".to_owned(), BASE_CONFIG)
+        );
+        assert!(layers.warnings.is_empty());
+    }
+
+    #[test]
+    fn preset_sets_keys_left_at_default_values() {
+        let mut layers = Layers::default();
+
+        layer(
+            &mut layers,
+            LayerKind::Base,
+            BASE_CONFIG,
+            "preset = \"large_mod\"\n[heap_sizes]\nsound = 3\nsfx = 8\n",
+        );
+
+        assert_eq!(
+            value(&layers, "heap_sizes.sound"),
+            ("4".to_owned(), "ds2s_heap_x.toml (preset large_mod)")
+        );
+        assert_eq!(
+            value(&layers, "heap_sizes.sfx"),
+            ("8".to_owned(), BASE_CONFIG)
+        );
+    }
+
+    #[test]
+    fn fragments_skip_values_below_defaults() {
+        let mut layers = Layers::default();
+
+        layer(
+            &mut layers,
+            LayerKind::Fragment,
+            "ds2s_heap_x.d/a.toml",
+            "[heap_sizes]\nsound = 2\nhavok = 8\n",
+        );
+
+        assert!(!layers.entries.contains_key("heap_sizes.sound"));
+        assert_eq!(
+            value(&layers, "heap_sizes.havok"),
+            ("8".to_owned(), "ds2s_heap_x.d/a.toml")
+        );
+    }
+
+    #[test]
+    fn fragments_warn_about_incomparable_sizes() {
+        let mut layers = Layers::default();
+
+        layer(
+            &mut layers,
+            LayerKind::Base,
+            BASE_CONFIG,
+            "[heap_sizes]\nsound = 4\n",
+        );
+        layer(
+            &mut layers,
+            LayerKind::Fragment,
+            "ds2s_heap_x.d/a.toml",
+            "[heap_sizes]\nsound = \"1GiB\"\n",
+        );
+
+        assert_eq!(
+            value(&layers, "heap_sizes.sound"),
+            ("\"1GiB\"".to_owned(), "ds2s_heap_x.d/a.toml")
+        );
+        assert_eq!(
+            layers.warnings,
+            [
+                "`heap_sizes.sound` = \"1GiB\" from ds2s_heap_x.d/a.toml cannot be compared \
+                 with 4 from ds2s_heap_x.toml, using \"1GiB\""
+            ]
+        );
+    }
+
+    #[test]
+    fn user_config_replaces_values() {
+        let mut layers = Layers::default();
+
+        layer(
+            &mut layers,
+            LayerKind::Fragment,
+            "ds2s_heap_x.d/a.toml",
+            "[heap_sizes]\nsound = 8\n",
+        );
+        layer(
+            &mut layers,
+            LayerKind::User,
+            USER_CONFIG,
+            "[heap_sizes]\nsound = 2\n",
+        );
+
+        assert_eq!(
+            value(&layers, "heap_sizes.sound"),
+            ("2".to_owned(), USER_CONFIG)
+        );
+
+        let config = layers.to_config().unwrap().normalize();
+
+        assert_eq!(config.heap_sizes.sound, HeapSize::Multiplier(4.0));
+        assert_eq!(config.heap_sizes.havok, HeapSize::Multiplier(8.0));
+    }
+
+    #[test]
+    fn presets_apply_before_their_layer() {
+        let mut layers = Layers::default();
+
+        layer(
+            &mut layers,
+            LayerKind::Base,
+            BASE_CONFIG,
+            "preset = \"vanilla\"\n[heap_sizes]\nsound = 2\n",
+        );
+
+        assert_eq!(
+            value(&layers, "heap_sizes.sound"),
+            ("2".to_owned(), BASE_CONFIG)
+        );
+        assert_eq!(
+            value(&layers, "heap_sizes.havok"),
+            ("1".to_owned(), "ds2s_heap_x.toml (preset vanilla)")
+        );
+        assert!(!layers.entries.contains_key("preset"));
+    }
+}
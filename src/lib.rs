@@ -1,14 +1,159 @@
-use std::path::Path;
+#[cfg(windows)]
+use std::{env, fs, path::Path};
 
-use config::Config;
+#[cfg(windows)]
+use windows::{core::HSTRING, Win32::System::Diagnostics::Debug::OutputDebugStringW};
 
-mod config;
+#[cfg(windows)]
+use config::HeapSizeConfig;
+#[cfg(windows)]
+use definitions::{GroupState, PatchSet, PatchSetError};
+#[cfg(windows)]
+use log::Log;
+#[cfg(windows)]
+use memory::ProcessMemory;
+#[cfg(windows)]
+use patches::{PatchError, SiteTable};
+
+pub mod budget;
+pub mod builds;
+pub mod config;
+pub mod definitions;
+pub mod expr;
+pub mod layers;
+pub mod log;
+pub mod memory;
+pub mod migration;
+pub mod overrides;
+pub mod patches;
+pub mod pe;
+pub mod presets;
+pub mod scan;
+
+#[cfg(windows)]
 mod exports;
-mod patches;
+#[cfg(windows)]
 mod version;
 
+#[cfg(windows)]
 fn init_dll(dll_path: &Path) -> bool {
-    let config = Config::read_or_create_default(dll_path);
+    let mut log = Log::create(dll_path);
+
+    log.write(concat!("ds2s_heap_x ", env!("CARGO_PKG_VERSION")));
+
+    match try_init_dll(dll_path, &mut log) {
+        Ok(()) => true,
+        Err(err) => {
+            log.write(format_args!("error: {err}"));
+
+            let message = HSTRING::from(format!("ds2s_heap_x: {err}\n"));
+
+            unsafe { OutputDebugStringW(&message) };
+
+            false
+        }
+    }
+}
+
+#[cfg(windows)]
+fn try_init_dll(dll_path: &Path, log: &mut Log) -> Result<(), String> {
+    let config = layers::read(dll_path, log);
+
+    let heap_sizes = HeapSizeConfig::NAMES
+        .iter()
+        .filter_map(|&name| Some(format!("{name} {}", config.heap_sizes.get(name)?)))
+        .collect::<Vec<_>>();
+
+    log.write(format_args!("heap sizes: {}", heap_sizes.join(", ")));
+
+    let version = version::get_file_version()
+        .map_err(|err| format!("failed to read the DarkSoulsII.exe version: {err}"))?;
+
+    log.write(format_args!("DarkSoulsII.exe version: {version}"));
+
+    let patch_set =
+        PatchSet::read_or_embedded(dll_path, &version).map_err(|err| err.to_string())?;
+
+    let exe_path =
+        env::current_exe().map_err(|err| format!("failed to find DarkSoulsII.exe: {err}"))?;
+
+    // Unchecked sites would be patched twice, compounding them. The game runs as patched
+    // offline, like when the budget refuses the heaps.
+    if builds::journal_path(&exe_path).exists() {
+        log.write(format_args!(
+            "{} was already patched by ds2s_heap_x_patcher, nothing was patched, restore it \
+             with --restore to use heap_x",
+            exe_path.display()
+        ));
+
+        return Ok(());
+    }
+
+    let exe =
+        fs::read(&exe_path).map_err(|err| format!("failed to read DarkSoulsII.exe: {err}"))?;
+
+    let status = match builds::identify(&patch_set, &exe, config.allow_unknown_builds) {
+        Ok(status) => status,
+        // The game runs unpatched, like when the budget refuses the heaps.
+        Err(err @ PatchSetError::UnknownHash { .. }) => {
+            log.write(format_args!("{err}, nothing was patched"));
+
+            return Ok(());
+        }
+        Err(err) => return Err(err.to_string()),
+    };
+
+    log.write(format_args!("DarkSoulsII.exe build: {status}"));
+
+    let mut memory = ProcessMemory::current().map_err(|err| err.to_string())?;
+
+    let report = match patches::place_all(&mut memory, &config, &patch_set) {
+        Ok(report) => report,
+        // `exceeded = "refuse"`, the game runs with its vanilla heaps.
+        Err(PatchError::Budget(err)) => {
+            log.write(format_args!("heaps: {err}, nothing was patched"));
+
+            return Ok(());
+        }
+        Err(err) => return Err(err.to_string()),
+    };
+
+    log.write(format_args!("heaps: {}", report.budget));
+
+    let unchecked = report.sites.iter().filter(|site| !site.checked).count();
+
+    if unchecked > 0 {
+        log.write(format_args!(
+            "warning: {unchecked} site(s) have no recorded vanilla value and are not checked"
+        ));
+    }
+
+    if config.dry_run {
+        for (group, state) in &report.groups {
+            if *state != GroupState::Enabled {
+                log.write(format_args!("{group}: skipped, {state}"));
+            }
+        }
+
+        log.write("dry run, nothing was written:");
+        log.write(SiteTable(&report.sites));
+
+        return Ok(());
+    }
+
+    for (group, state) in &report.groups {
+        if *state != GroupState::Enabled {
+            log.write(format_args!("{group}: skipped, {state}"));
+
+            continue;
+        }
+
+        log.write(format_args!("{group}: applied"));
+
+        for site in report.sites.iter().filter(|site| site.group == *group) {
+            log.write(format_args!("  {site}"));
+        }
+    }
 
-    version::verify() && patches::place_all(&config).is_ok()
+    Ok(())
 }
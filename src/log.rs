@@ -0,0 +1,42 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::config::dll_dir_from_path;
+
+/// "ds2s_heap_x.log" next to the DLL, describing what heap_x did at startup.
+///
+/// The log is truncated on each launch. Logging is best effort, a log that
+/// cannot be created or written to is silently ignored.
+pub struct Log {
+    out: Option<Box<dyn Write>>,
+}
+
+impl Log {
+    pub fn create(dll_path: &Path) -> Self {
+        let file = dll_dir_from_path(dll_path).and_then(|mut path| {
+            path.push("ds2s_heap_x.log");
+            File::create(path).ok()
+        });
+
+        Self {
+            out: file.map(|file| Box::new(file) as Box<dyn Write>),
+        }
+    }
+
+    /// A log written to stderr, for the patcher.
+    pub fn stderr() -> Self {
+        Self {
+            out: Some(Box::new(io::stderr())),
+        }
+    }
+
+    pub fn write(&mut self, message: impl fmt::Display) {
+        if let Some(out) = &mut self.out {
+            let _ = writeln!(out, "{message}");
+        }
+    }
+}
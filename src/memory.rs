@@ -0,0 +1,361 @@
+use std::fmt;
+
+use crate::pe::{PeError, PeHeaders};
+
+#[cfg(windows)]
+use windows::{
+    core::{Error as WindowsError, PCWSTR},
+    Win32::System::{
+        LibraryLoader::GetModuleHandleW,
+        Memory::{VirtualProtect, PAGE_EXECUTE_READWRITE, PAGE_PROTECTION_FLAGS},
+        SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX},
+    },
+};
+
+/// Memory the patches are read from and written to, addressed by RVA.
+///
+/// Implemented for the live game process and for plain byte buffers, so the
+/// same patch set can be placed in-process or against a dumped image.
+///
+pub trait Memory {
+    fn read(&self, rva: usize, buf: &mut [u8]) -> Result<(), MemoryError>;
+
+    fn write(&mut self, rva: usize, bytes: &[u8]) -> Result<(), MemoryError>;
+
+    /// Makes `len` bytes at `rva` writable before they are patched.
+    fn protect(&mut self, rva: usize, len: usize) -> Result<(), MemoryError>;
+
+    /// All bytes backing the memory and the RVA they start at, for signature scanning.
+    fn bytes(&self) -> Result<(usize, &[u8]), MemoryError>;
+
+    /// The PE headers of the image, if the memory includes them.
+    fn headers(&self) -> Option<&PeHeaders> {
+        None
+    }
+
+    /// How many bytes the game can still allocate, if known, to keep the heaps within it.
+    fn available_memory(&self) -> Option<u64> {
+        None
+    }
+
+    fn read_u32(&self, rva: usize) -> Result<u32, MemoryError> {
+        let mut bytes = [0; 4];
+
+        self.read(rva, &mut bytes)?;
+
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn write_u32(&mut self, rva: usize, val: u32) -> Result<(), MemoryError> {
+        self.write(rva, &val.to_le_bytes())
+    }
+}
+
+#[derive(Debug)]
+pub enum MemoryError {
+    OutOfBounds {
+        rva: usize,
+        len: usize,
+    },
+    MissingSection(&'static str),
+    InvalidImage(PeError),
+    #[cfg(windows)]
+    Windows(WindowsError),
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfBounds { rva, len } => {
+                write!(f, "{len} bytes at RVA {rva:#x} are out of bounds")
+            }
+            Self::MissingSection(name) => write!(f, "image has no {name} section"),
+            Self::InvalidImage(err) => write!(f, "{err}"),
+            #[cfg(windows)]
+            Self::Windows(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<WindowsError> for MemoryError {
+    fn from(err: WindowsError) -> Self {
+        Self::Windows(err)
+    }
+}
+
+/// An in-memory image section (or a whole image) starting at `base_rva`.
+pub struct BufferMemory {
+    base_rva: usize,
+    bytes: Vec<u8>,
+}
+
+impl BufferMemory {
+    pub fn new(base_rva: usize, bytes: Vec<u8>) -> Self {
+        Self { base_rva, bytes }
+    }
+
+    pub fn base_rva(&self) -> usize {
+        self.base_rva
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn range(&self, rva: usize, len: usize) -> Result<std::ops::Range<usize>, MemoryError> {
+        rva.checked_sub(self.base_rva)
+            .and_then(|start| Some(start..start.checked_add(len)?))
+            .filter(|range| range.end <= self.bytes.len())
+            .ok_or(MemoryError::OutOfBounds { rva, len })
+    }
+}
+
+impl Memory for BufferMemory {
+    fn read(&self, rva: usize, buf: &mut [u8]) -> Result<(), MemoryError> {
+        let range = self.range(rva, buf.len())?;
+
+        buf.copy_from_slice(&self.bytes[range]);
+
+        Ok(())
+    }
+
+    fn write(&mut self, rva: usize, bytes: &[u8]) -> Result<(), MemoryError> {
+        let range = self.range(rva, bytes.len())?;
+
+        self.bytes[range].copy_from_slice(bytes);
+
+        Ok(())
+    }
+
+    fn protect(&mut self, rva: usize, len: usize) -> Result<(), MemoryError> {
+        self.range(rva, len).map(|_| ())
+    }
+
+    fn bytes(&self) -> Result<(usize, &[u8]), MemoryError> {
+        Ok((self.base_rva, &self.bytes))
+    }
+}
+
+/// A PE image as laid out on disk, addressed by RVA through its section headers.
+pub struct FileMemory {
+    headers: PeHeaders,
+    bytes: Vec<u8>,
+}
+
+impl FileMemory {
+    pub fn new(headers: PeHeaders, bytes: Vec<u8>) -> Self {
+        Self { headers, bytes }
+    }
+
+    /// The whole file, headers included.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn range(&self, rva: usize, len: usize) -> Result<std::ops::Range<usize>, MemoryError> {
+        self.headers
+            .rva_to_offset(rva, len)
+            .map(|start| start..start + len)
+            .filter(|range| range.end <= self.bytes.len())
+            .ok_or(MemoryError::OutOfBounds { rva, len })
+    }
+}
+
+impl Memory for FileMemory {
+    fn read(&self, rva: usize, buf: &mut [u8]) -> Result<(), MemoryError> {
+        let range = self.range(rva, buf.len())?;
+
+        buf.copy_from_slice(&self.bytes[range]);
+
+        Ok(())
+    }
+
+    fn write(&mut self, rva: usize, bytes: &[u8]) -> Result<(), MemoryError> {
+        let range = self.range(rva, bytes.len())?;
+
+        self.bytes[range].copy_from_slice(bytes);
+
+        Ok(())
+    }
+
+    fn protect(&mut self, rva: usize, len: usize) -> Result<(), MemoryError> {
+        self.range(rva, len).map(|_| ())
+    }
+
+    /// Only the `.text` section is contiguous in both the file and the mapped image.
+    fn bytes(&self) -> Result<(usize, &[u8]), MemoryError> {
+        let text = self
+            .headers
+            .section(".text")
+            .ok_or(MemoryError::MissingSection(".text"))?;
+
+        let range = self.range(text.virtual_address as usize, text.raw_len())?;
+
+        Ok((text.virtual_address as usize, &self.bytes[range]))
+    }
+
+    fn headers(&self) -> Option<&PeHeaders> {
+        Some(&self.headers)
+    }
+}
+
+/// The main module of the current process, i.e. the mapped DarkSoulsII.exe image.
+#[cfg(windows)]
+pub struct ProcessMemory {
+    base_addr: usize,
+    headers: PeHeaders,
+}
+
+#[cfg(windows)]
+impl ProcessMemory {
+    pub fn current() -> Result<Self, MemoryError> {
+        // The headers are expected to fit in the first page of the image.
+        const HEADERS_SIZE: usize = 0x1000;
+
+        let base_addr = unsafe { GetModuleHandleW(PCWSTR::null())?.0 as usize };
+
+        let headers = unsafe { std::slice::from_raw_parts(base_addr as *const u8, HEADERS_SIZE) };
+
+        Ok(Self {
+            base_addr,
+            headers: PeHeaders::parse(headers).map_err(MemoryError::InvalidImage)?,
+        })
+    }
+
+    fn addr(&self, rva: usize, len: usize) -> Result<usize, MemoryError> {
+        rva.checked_add(len)
+            .filter(|&end| end <= self.headers.image_size as usize)
+            .map(|_| self.base_addr + rva)
+            .ok_or(MemoryError::OutOfBounds { rva, len })
+    }
+}
+
+#[cfg(windows)]
+impl Memory for ProcessMemory {
+    fn read(&self, rva: usize, buf: &mut [u8]) -> Result<(), MemoryError> {
+        let addr = self.addr(rva, buf.len())?;
+
+        unsafe {
+            let ptr = addr as *const u8;
+
+            ptr.copy_to_nonoverlapping(buf.as_mut_ptr(), buf.len());
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, rva: usize, bytes: &[u8]) -> Result<(), MemoryError> {
+        let addr = self.addr(rva, bytes.len())?;
+
+        unsafe {
+            let ptr = addr as *mut u8;
+
+            ptr.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+        }
+
+        Ok(())
+    }
+
+    fn protect(&mut self, rva: usize, len: usize) -> Result<(), MemoryError> {
+        let addr = self.addr(rva, len)?;
+
+        unsafe {
+            VirtualProtect(
+                addr as _,
+                len,
+                PAGE_EXECUTE_READWRITE,
+                &mut PAGE_PROTECTION_FLAGS::default(),
+            )?
+        }
+
+        Ok(())
+    }
+
+    fn bytes(&self) -> Result<(usize, &[u8]), MemoryError> {
+        let text = self
+            .headers
+            .section(".text")
+            .ok_or(MemoryError::MissingSection(".text"))?;
+
+        let addr = self.addr(text.virtual_address as usize, text.virtual_len())?;
+
+        let bytes = unsafe { std::slice::from_raw_parts(addr as *const u8, text.virtual_len()) };
+
+        Ok((text.virtual_address as usize, bytes))
+    }
+
+    fn headers(&self) -> Option<&PeHeaders> {
+        Some(&self.headers)
+    }
+
+    /// The memory that can still be committed, limited by the free address space.
+    fn available_memory(&self) -> Option<u64> {
+        let mut status = MEMORYSTATUSEX {
+            dwLength: size_of::<MEMORYSTATUSEX>() as u32,
+            ..Default::default()
+        };
+
+        unsafe { GlobalMemoryStatusEx(&mut status) }.ok()?;
+
+        Some(status.ullAvailPageFile.min(status.ullAvailVirtual))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_writes_by_rva() {
+        let mut memory = BufferMemory::new(0x1000, vec![0; 0x10]);
+
+        memory.write(0x1004, &[1, 2, 3]).unwrap();
+        memory.write_u32(0x100c, 0x12345678).unwrap();
+
+        let mut buf = [0; 3];
+
+        memory.read(0x1004, &mut buf).unwrap();
+
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(memory.read_u32(0x100c).unwrap(), 0x12345678);
+        assert_eq!(&memory.as_bytes()[0x0c..], [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(memory.base_rva(), 0x1000);
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_access() {
+        let mut memory = BufferMemory::new(0x1000, vec![0; 0x10]);
+
+        for (rva, len) in [(0xfff, 1), (0x100d, 4), (0x1010, 1), (usize::MAX, 2)] {
+            assert!(
+                matches!(
+                    memory.read(rva, &mut vec![0; len]),
+                    Err(MemoryError::OutOfBounds { rva: err_rva, len: err_len })
+                        if err_rva == rva && err_len == len
+                ),
+                "{rva:#x}"
+            );
+            assert!(memory.write(rva, &vec![0xff; len]).is_err());
+            assert!(memory.protect(rva, len).is_err());
+        }
+
+        assert_eq!(memory.into_bytes(), [0; 0x10]);
+    }
+
+    #[test]
+    fn protects_in_bounds_ranges() {
+        let mut memory = BufferMemory::new(0, vec![0; 4]);
+
+        assert!(memory.protect(0, 4).is_ok());
+        assert!(memory.protect(4, 0).is_ok());
+    }
+}
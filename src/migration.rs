@@ -0,0 +1,171 @@
+use std::{fs, io, path::Path};
+
+use toml_edit::{Decor, DocumentMut, Item, Table};
+
+use crate::config::DEFAULT_CONFIG;
+
+/// The current config format version:
+///
+/// 1. The original format, without `config_version`.
+/// 2. Adds `config_version` and `[limits]`, heap sizes can be fractional or absolute.
+/// 3. Adds `[budget]`.
+/// 4. Adds a toggle for every patch group, `[patch_heaps]` for the heaps.
+/// 5. Adds `dry_run`.
+/// 6. Adds `allow_unknown_builds`.
+pub const CONFIG_VERSION: u32 = 6;
+
+pub(crate) fn legacy_config_version() -> u32 {
+    1
+}
+
+/// Upgrades the config at `config_path` to `CONFIG_VERSION` in place.
+pub fn migrate_file(config_path: &Path) -> io::Result<()> {
+    let mut document = fs::read_to_string(config_path)?
+        .parse::<DocumentMut>()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    migrate(&mut document);
+
+    fs::write(config_path, document.to_string())
+}
+
+/// Upgrades a config document to `CONFIG_VERSION`, keeping the user's values, comments
+/// and formatting.
+///
+/// Versions up to 6 only added keys, which are copied from `DEFAULT_CONFIG` along with
+/// their comments. Renamed or removed keys would be handled here as well.
+pub fn migrate(document: &mut DocumentMut) {
+    let defaults = DEFAULT_CONFIG
+        .parse::<DocumentMut>()
+        .expect("valid default config");
+
+    // The default values would override the preset.
+    if !document.contains_key("preset") {
+        add_missing_keys(document.as_table_mut(), defaults.as_table());
+    }
+
+    document["config_version"] = toml_edit::value(i64::from(CONFIG_VERSION));
+}
+
+fn add_missing_keys(table: &mut Table, defaults: &Table) {
+    for (key, default) in defaults.iter() {
+        let (key, _) = defaults.get_key_value(key).expect("default key");
+
+        if let (Some(Item::Table(table)), Item::Table(defaults)) = (table.get_mut(key), default) {
+            add_missing_keys(table, defaults);
+
+            continue;
+        }
+
+        if table.contains_key(key) {
+            continue;
+        }
+
+        let mut key = key.clone();
+        let mut default = default.clone();
+
+        match &mut default {
+            Item::Table(default) => {
+                // Placed after the user's tables.
+                default.set_position(usize::MAX);
+
+                own_comment(default.decor_mut());
+            }
+            _ => own_comment(key.leaf_decor_mut()),
+        }
+
+        table.insert_formatted(&key, default);
+    }
+}
+
+/// Only keeps the comment paragraph right above a key, the paragraphs before it,
+/// like the header of `DEFAULT_CONFIG`, do not belong to it.
+fn own_comment(decor: &mut Decor) {
+    let Some(prefix) = decor.prefix().and_then(|prefix| prefix.as_str()) else {
+        return;
+    };
+
+    if let Some((_, comment)) = prefix.rsplit_once("\n\n") {
+        decor.set_prefix(format!("\n{comment}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, HeapSize};
+
+    const V1_CONFIG: &str = "\
+# My settings.
+patch_character_limit = false
+patch_soundbank_limit = true
+heap_size_multiplier = 1
+
+[heap_sizes]
+# Large sound mod.
+sound = 8
+havok = 2
+";
+
+    fn migrated(config: &str) -> String {
+        let mut document = config.parse::<DocumentMut>().unwrap();
+
+        migrate(&mut document);
+
+        document.to_string()
+    }
+
+    #[test]
+    fn migrates_v1_config() {
+        let (config, _) = Config::parse(V1_CONFIG).unwrap();
+
+        assert_eq!(config.config_version, 1);
+
+        let migrated = migrated(V1_CONFIG);
+
+        // The user's values and comments are kept.
+        assert!(migrated.starts_with("# My settings.\npatch_character_limit = false\n"));
+        assert!(migrated.contains("# Large sound mod.\nsound = 8\n"));
+
+        let (config, warnings) = Config::parse(&migrated).unwrap();
+
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(config.config_version, CONFIG_VERSION);
+        assert!(!config.patch_character_limit);
+        assert_eq!(config.heap_sizes.sound, HeapSize::Multiplier(8.0));
+        assert_eq!(config.heap_sizes.havok, HeapSize::Multiplier(2.0));
+        // Added with their default values.
+        assert_eq!(config.heap_sizes.morpheme, HeapSize::Multiplier(4.0));
+        assert_eq!(config.limits.character, 1024);
+
+        let document = migrated.parse::<DocumentMut>().unwrap();
+
+        for key in [
+            "limits",
+            "budget",
+            "patch_heaps",
+            "dry_run",
+            "allow_unknown_builds",
+        ] {
+            assert!(document.contains_key(key), "{key}");
+        }
+    }
+
+    #[test]
+    fn migration_is_idempotent() {
+        let migrated = migrated(V1_CONFIG);
+
+        assert_eq!(self::migrated(&migrated), migrated);
+        assert_eq!(self::migrated(DEFAULT_CONFIG), DEFAULT_CONFIG);
+    }
+
+    #[test]
+    fn keeps_preset_configs_minimal() {
+        let migrated = migrated("preset = \"large_mod\"\n[heap_sizes]\nsound = 8\n");
+
+        assert_eq!(
+            migrated,
+            format!("preset = \"large_mod\"\nconfig_version = {CONFIG_VERSION}\n[heap_sizes]\nsound = 8\n")
+        );
+    }
+}
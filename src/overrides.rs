@@ -0,0 +1,158 @@
+use std::{env, ffi::OsString};
+
+use crate::{config::Config, presets::Preset};
+
+/// Environment variables `DS2S_HEAP_X_<KEY>` set a config key, `DS2S_HEAP_X_HEAP_SIZES_SOUND=8`.
+pub const ENV_PREFIX: &str = "DS2S_HEAP_X_";
+/// Command line arguments `--heapx-<key>=<value>` set a config key,
+/// `--heapx-heap-sizes-sound=8`.
+pub const ARG_PREFIX: &str = "--heapx-";
+
+/// A config key set by an environment variable or a command line argument.
+pub struct Override {
+    /// The variable or argument.
+    pub source: String,
+    /// The key, as a config table with a single value.
+    pub table: toml::Table,
+    /// The preset, if the key is `preset`.
+    pub preset: Option<Preset>,
+}
+
+/// Reads the overrides set by the environment, then by the command line in order.
+///
+/// Variables and arguments that do not name a key or have an invalid value are returned
+/// as errors.
+pub fn read() -> Vec<Result<Override, String>> {
+    let mut vars = env::vars_os()
+        .filter_map(|(name, value)| {
+            let name = name.into_string().ok()?;
+            let key = name.strip_prefix(ENV_PREFIX)?.to_owned();
+
+            Some((key, name, value))
+        })
+        .collect::<Vec<_>>();
+
+    // The environment is unordered, a preset is applied first so the other variables
+    // override it.
+    vars.sort_by_key(|(key, ..)| (!key.eq_ignore_ascii_case("preset"), key.clone()));
+
+    let vars = vars
+        .into_iter()
+        .map(|(key, name, value)| parse(name, &key, value));
+
+    let args = env::args_os().skip(1).filter_map(|arg| {
+        let arg = arg.into_string().ok()?;
+        let (key, value) = arg.strip_prefix(ARG_PREFIX)?.split_once('=')?;
+
+        Some(parse(arg.clone(), key, value.into()))
+    });
+
+    vars.chain(args).collect()
+}
+
+fn parse(source: String, name: &str, value: OsString) -> Result<Override, String> {
+    let name = name.to_lowercase().replace(['-', '.'], "_");
+
+    let Some(key) = Config::keys()
+        .into_iter()
+        .find(|key| key.replace('.', "_") == name)
+    else {
+        return Err(format!("{source}: unknown key"));
+    };
+
+    let Some(value) = value.to_str() else {
+        return Err(format!("{source}: invalid UTF-8"));
+    };
+
+    // Anything that is not a TOML value, like `768MiB`, is a string.
+    let value = toml::from_str::<toml::Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_owned()));
+
+    let table = match key.split_once('.') {
+        Some((section, name)) => toml::Table::from_iter([(
+            section.to_owned(),
+            toml::Value::Table(toml::Table::from_iter([(name.to_owned(), value)])),
+        )]),
+        None => toml::Table::from_iter([(key, value)]),
+    };
+
+    let preset = Config::from_table(table.clone())
+        .map_err(|err| format!("{source}: {err}"))?
+        .preset;
+
+    Ok(Override {
+        source,
+        table,
+        preset,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_arg(arg: &str) -> Result<Override, String> {
+        let (key, value) = arg
+            .strip_prefix(ARG_PREFIX)
+            .and_then(|arg| arg.split_once('='))
+            .unwrap();
+
+        parse(arg.to_owned(), key, value.into())
+    }
+
+    #[test]
+    fn matches_key_spellings() {
+        for (name, value) in [
+            ("HEAP_SIZES_SOUND", "8"),
+            ("heap-sizes-sound", "8"),
+            ("heap_sizes.sound", "8"),
+            ("Heap.Sizes.Sound", "8"),
+        ] {
+            let Override { table, .. } = parse("test".to_owned(), name, value.into()).unwrap();
+
+            assert_eq!(table.to_string(), "[heap_sizes]\nsound = 8\n", "{name}");
+        }
+    }
+
+    #[test]
+    fn parses_values_as_toml_or_strings() {
+        let Override { source, table, .. } = parse_arg("--heapx-heap-sizes-sound=768MiB").unwrap();
+
+        assert_eq!(source, "--heapx-heap-sizes-sound=768MiB");
+        assert_eq!(table.to_string(), "[heap_sizes]\nsound = \"768MiB\"\n");
+
+        let Override { table, .. } = parse_arg("--heapx-patch-soundbank-limit=false").unwrap();
+
+        assert_eq!(table.to_string(), "patch_soundbank_limit = false\n");
+
+        let Override { table, .. } = parse_arg("--heapx-heap-sizes-sfx=1.5").unwrap();
+
+        assert_eq!(table.to_string(), "[heap_sizes]\nsfx = 1.5\n");
+    }
+
+    #[test]
+    fn reads_presets() {
+        let Override { preset, .. } = parse_arg("--heapx-preset=extreme").unwrap();
+
+        assert_eq!(preset, Some(Preset::Extreme));
+
+        let Override { preset, .. } = parse_arg("--heapx-limits-character=2048").unwrap();
+
+        assert_eq!(preset, None);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_invalid_values() {
+        assert_eq!(
+            parse_arg("--heapx-heap-sizes-soud=8").err().unwrap(),
+            "--heapx-heap-sizes-soud=8: unknown key"
+        );
+        assert!(parse_arg("--heapx-limits-character=many")
+            .err()
+            .unwrap()
+            .starts_with("--heapx-limits-character=many: "));
+        assert!(parse_arg("--heapx-preset=huge").is_err());
+    }
+}
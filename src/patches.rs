@@ -1,437 +1,1127 @@
-use windows::{
-    core::{Result as WindowsResult, PCWSTR},
-    Win32::System::{
-        LibraryLoader::GetModuleHandleW,
-        Memory::{VirtualProtect, PAGE_EXECUTE_READWRITE, PAGE_PROTECTION_FLAGS},
-    },
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    budget::{self, BudgetError, BudgetReport, HeapBase},
+    config::{Config, HeapSize},
+    definitions::{GroupState, PatchDef, PatchOp, PatchSet},
+    expr::{Expr, ExprError},
+    memory::{Memory, MemoryError},
+    scan::{Pattern, ScanError},
 };
 
-use crate::config::Config;
+/// Places every patch of `patch_set` whose group is enabled by `config`.
+pub fn place_all<M: Memory>(
+    memory: &mut M,
+    config: &Config,
+    patch_set: &PatchSet,
+) -> Result<PatchReport, PatchError> {
+    let mut patch_helper = PatchHelper::new(memory, config, patch_set);
+
+    let (heaps, global_base) = patch_helper.heap_bases()?;
+
+    let plan = budget::plan(
+        &heaps,
+        global_base,
+        &config.heap_sizes,
+        &config.budget,
+        patch_helper.memory.available_memory(),
+    )
+    .map_err(PatchError::Budget)?;
+
+    patch_helper.scaled_sizes = plan.sizes;
+
+    for patch in &patch_set.patches {
+        if patch_set.group_enabled(&patch.group, config) {
+            patch_helper.group(&patch.group);
+            patch_helper.place(patch)?;
+        }
+    }
 
-pub fn place_all(config: &Config) -> WindowsResult<()> {
-    let mut patch_helper = PatchHelper::new(config)?;
+    let sites = patch_helper.apply()?;
 
-    // Graphics Main Heap:
-    patch_helper.mul_u32(0xaef57c + 3, config.heap_sizes.graphics, true)?;
+    let groups = patch_set
+        .groups
+        .iter()
+        .map(|group| {
+            (
+                group.name.clone(),
+                patch_set.group_state(&group.name, config),
+            )
+        })
+        .collect();
+
+    Ok(PatchReport {
+        groups,
+        sites,
+        budget: plan.report,
+    })
+}
 
-    // File Data Heap:
-    patch_helper.mul_u32(0xaef59c + 3, config.heap_sizes.file_data, false)?;
+/// What `place_all` wrote.
+pub struct PatchReport {
+    /// Every group of the patch set and whether it was applied.
+    pub groups: Vec<(String, GroupState)>,
+    /// Every written site, in order.
+    pub sites: Vec<SiteReport>,
+    pub budget: BudgetReport,
+}
 
-    // Sound Sys Heap:
-    patch_helper.mul_u32(0xaef5a3 + 4, config.heap_sizes.sound, true)?;
+pub struct SiteReport {
+    pub group: String,
+    pub name: String,
+    pub offset: usize,
+    pub original: Vec<u8>,
+    pub patched: Vec<u8>,
+    /// How much the site grows the global heap by.
+    pub global_heap_growth: u64,
+    /// Whether the original value was checked against the recorded vanilla value.
+    pub checked: bool,
+}
 
-    // Network Heap:
-    patch_helper.mul_u32(0xaef5ab + 3, config.heap_sizes.network, false)?;
+impl fmt::Display for SiteReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DarkSoulsII.exe+{:#x} ({}):", self.offset, self.name)?;
 
-    // String Heap:
-    patch_helper.mul_u32(0xaef5b2 + 3, config.heap_sizes.string_data, false)?;
+        write_bytes(f, &self.original)?;
+        f.write_str(" ->")?;
+        write_bytes(f, &self.patched)
+    }
+}
 
-    // Temp Heap:
-    patch_helper.mul_u32(0xaef5b9 + 3, config.heap_sizes.temp, true)?;
+/// The sites of a report as a table of their old and new values, as printed by `dry_run`.
+pub struct SiteTable<'a>(pub &'a [SiteReport]);
+
+impl fmt::Display for SiteTable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = ["site", "group", "name", "old", "new", "global heap"].map(String::from);
+
+        let rows = self
+            .0
+            .iter()
+            .map(|site| {
+                [
+                    format!("DarkSoulsII.exe+{:#x}", site.offset),
+                    site.group.clone(),
+                    site.name.clone(),
+                    SiteValue(&site.original).to_string(),
+                    SiteValue(&site.patched).to_string(),
+                    match site.global_heap_growth {
+                        0 => String::new(),
+                        growth => format!("+{growth:#x}"),
+                    },
+                ]
+            })
+            .collect::<Vec<_>>();
 
-    // Temp2 Heap:
-    patch_helper.mul_u32(0xaef5c0 + 3, config.heap_sizes.temp2, true)?;
+        let mut widths = [0; 6];
 
-    // Debug Heap:
-    patch_helper.mul_u32(0xaef5c7 + 3, config.heap_sizes.debug, false)?;
+        for row in std::iter::once(&header).chain(&rows) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
 
-    // Gui Default Heap:
-    patch_helper.mul_u32(0xaef5ce + 4, config.heap_sizes.gui, false)?;
+        for (i, row) in std::iter::once(&header).chain(&rows).enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
 
-    // Regulation Heap:
-    patch_helper.mul_u32(0x1c3512 + 2, config.heap_sizes.regulation, true)?;
-    patch_helper.mul_u32(0x1c352e + 2, config.heap_sizes.regulation, false)?;
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect::<Vec<_>>()
+                .join("  ");
 
-    // Menu Heap:
-    patch_helper.mul_u32(0x1c357e + 2, config.heap_sizes.menu, true)?;
-    patch_helper.mul_u32(0x1c359a + 2, config.heap_sizes.menu, false)?;
+            f.write_str(line.trim_end())?;
+        }
 
-    // FaceGen Heap:
-    patch_helper.mul_u32(0x1c35f3 + 2, config.heap_sizes.facegen, true)?;
-    patch_helper.mul_u32(0x1c360f + 2, config.heap_sizes.facegen, false)?;
+        Ok(())
+    }
+}
 
-    // Player Heap:
-    patch_helper.mul_u32(0x1c3670 + 2, config.heap_sizes.player, true)?;
-    patch_helper.mul_u32(0x1c368c + 2, config.heap_sizes.player, false)?;
+/// A 32-bit operand as hex, or the bytes of any other site.
+struct SiteValue<'a>(&'a [u8]);
 
-    // Sfx System Heap:
-    patch_helper.mul_u32(0x1c372c + 2, config.heap_sizes.sfx, true)?;
-    patch_helper.mul_u32(0x1c3748 + 2, config.heap_sizes.sfx, false)?;
+impl fmt::Display for SiteValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match <[u8; 4]>::try_from(self.0) {
+            Ok(bytes) => write!(f, "{:#010x}", u32::from_le_bytes(bytes)),
+            Err(_) => {
+                let bytes = self.0.iter().map(|byte| format!("{byte:02X}"));
 
-    // Havok Heap:
-    patch_helper.mul_u32(0x1c37a1 + 2, config.heap_sizes.havok, true)?;
-    patch_helper.mul_u32(0x1c37c0 + 2, config.heap_sizes.havok, false)?;
+                f.write_str(&bytes.collect::<Vec<_>>().join(" "))
+            }
+        }
+    }
+}
 
-    // SceneGraph Heap:
-    patch_helper.mul_u32(0x1c3819 + 2, config.heap_sizes.scene_graph, true)?;
-    patch_helper.mul_u32(0x1c3835 + 2, config.heap_sizes.scene_graph, false)?;
+fn write_bytes(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes {
+        write!(f, " {byte:02X}")?;
+    }
 
-    // Morpheme Heap:
-    patch_helper.mul_u32(0x1c388e + 2, config.heap_sizes.morpheme, true)?;
-    patch_helper.mul_u32(0x1c38aa + 2, config.heap_sizes.morpheme, false)?;
+    Ok(())
+}
 
-    // Global Heap:
-    patch_helper.set_global_heap_u32(0xaef595 + 3)?;
+/// Where a patch is placed in the game image.
+#[derive(Clone, Debug)]
+pub enum Site {
+    Rva(usize),
+    /// The operand at `operand` bytes into the only match of `pattern`.
+    Signature {
+        pattern: Pattern,
+        operand: usize,
+    },
+}
 
-    // Morpheme fixed size vector expansion:
-    patch_helper.patch_morpheme_limit()?;
+impl From<usize> for Site {
+    fn from(rva: usize) -> Self {
+        Self::Rva(rva)
+    }
+}
 
-    // Patch DLFixedVector containers limited to 32 character resource slots:
-    patch_helper.patch_character_resource_limit()?;
+/// Original contents a patch site must hold before it is written to.
+#[derive(Clone, Debug)]
+enum Expected {
+    Value(u32),
+    Bytes(Pattern),
+    /// The vanilla value has not been recorded, the site is written whatever it holds, so
+    /// it does not catch repacked or already patched executables.
+    Any,
+}
 
-    // Patch DLFixedVector container limited to 48 FMod soundbanks:
-    patch_helper.patch_soundbank_limit()?;
+impl Expected {
+    /// How many bytes at the site are checked.
+    fn len(&self) -> usize {
+        match self {
+            Self::Value(_) => 4,
+            Self::Bytes(pattern) => pattern.len(),
+            Self::Any => 0,
+        }
+    }
 
-    // Patch map destructor stack limit from 256 enemies:
-    patch_helper.patch_map_dtor_stack()?;
+    fn matches(&self, found: &[u8]) -> bool {
+        match self {
+            Self::Value(val) => found == val.to_le_bytes(),
+            Self::Bytes(pattern) => pattern.matches(found),
+            Self::Any => true,
+        }
+    }
+}
 
-    // Patch arbitrary 255 `EnemyGeneratorCtrl` limit:
-    patch_helper.set_u32(0x40e7d8 + 2, 0)?;
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Value(val) => write!(f, "{val:#010x}"),
+            Self::Bytes(pattern) => write!(f, "{pattern}"),
+            Self::Any => f.write_str("any"),
+        }
+    }
+}
 
-    Ok(())
+#[derive(Debug)]
+pub enum PatchError {
+    Memory(MemoryError),
+    /// The heaps do not fit in the memory budget, nothing has been written.
+    Budget(BudgetError),
+    Scan {
+        pattern: Pattern,
+        err: ScanError,
+    },
+    /// The site of `patch` does not lie in `section` of the game image, nothing has been written.
+    Section {
+        patch: String,
+        offset: usize,
+        len: usize,
+        section: String,
+    },
+    /// The new value of `patch`, the size of `heap` if it resizes one, does not fit in its
+    /// 32-bit operand, nothing has been written.
+    Overflow {
+        patch: String,
+        heap: Option<String>,
+        val: u64,
+    },
+    /// The value or expected value of a patch could not be evaluated.
+    Expression {
+        patch: String,
+        err: ExprError,
+    },
+    /// The listed sites do not hold their expected original values,
+    /// nothing has been written.
+    Mismatch(Vec<SiteMismatch>),
+    /// Writing a site of `group` failed, every site written before it has been restored.
+    Write {
+        group: String,
+        err: MemoryError,
+    },
+    /// Writing a site of `group` failed and restoring the sites written before it failed too,
+    /// the game image is left partially patched.
+    Rollback {
+        group: String,
+        err: MemoryError,
+        rollback_err: MemoryError,
+    },
 }
 
-struct PatchHelper<'a> {
-    config: &'a Config,
-    base_addr: usize,
-    global_heap_bonus: u32,
+#[derive(Debug)]
+pub struct SiteMismatch {
+    pub name: String,
+    pub offset: usize,
+    pub expected: String,
+    pub found: Vec<u8>,
 }
 
-impl<'a> PatchHelper<'a> {
-    fn new(config: &'a Config) -> WindowsResult<PatchHelper<'a>> {
-        unsafe {
-            GetModuleHandleW(PCWSTR::null()).map(|h| Self {
-                config,
-                base_addr: h.0 as usize,
-                global_heap_bonus: 0,
-            })
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Memory(err) => write!(f, "{err}"),
+            Self::Budget(err) => write!(f, "{err}"),
+            Self::Scan { pattern, err } => write!(f, "\"{pattern}\": {err}"),
+            Self::Section {
+                patch,
+                offset,
+                len,
+                section,
+            } => write!(
+                f,
+                "\"{patch}\": {len} byte(s) at DarkSoulsII.exe+{offset:#x} are not within {section}"
+            ),
+            Self::Overflow {
+                patch,
+                heap: Some(heap),
+                val,
+            } => write!(
+                f,
+                "\"{patch}\": the {heap} heap size of {val:#x} bytes does not fit in 32 bits"
+            ),
+            Self::Overflow {
+                patch,
+                heap: None,
+                val,
+            } => write!(f, "\"{patch}\": {val:#x} does not fit in 32 bits"),
+            Self::Expression { patch, err } => write!(f, "\"{patch}\": {err}"),
+            Self::Mismatch(mismatches) => {
+                write!(
+                    f,
+                    "{} patch site(s) do not match the expected original values:",
+                    mismatches.len()
+                )?;
+
+                for SiteMismatch {
+                    name,
+                    offset,
+                    expected,
+                    found,
+                } in mismatches
+                {
+                    write!(
+                        f,
+                        "\n  DarkSoulsII.exe+{offset:#x} ({name}): expected {expected}, found"
+                    )?;
+
+                    write_bytes(f, found)?;
+                }
+
+                Ok(())
+            }
+            Self::Write { group, err } => {
+                write!(f, "failed to patch {group}, all patches reverted: {err}")
+            }
+            Self::Rollback {
+                group,
+                err,
+                rollback_err,
+            } => write!(
+                f,
+                "failed to patch {group}: {err}, failed to revert patches: {rollback_err}"
+            ),
         }
     }
+}
 
-    fn set_u32(&mut self, offset: usize, val: u32) -> WindowsResult<()> {
-        Self::set_rwe_memory_u32(self.base_addr + offset)?;
+impl From<MemoryError> for PatchError {
+    fn from(err: MemoryError) -> Self {
+        Self::Memory(err)
+    }
+}
 
-        unsafe {
-            let ptr = (self.base_addr + offset) as *mut u32;
+/// A single staged write.
+struct Patch<'a> {
+    group: &'a str,
+    name: &'a str,
+    offset: usize,
+    expected: Expected,
+    bytes: Vec<u8>,
+    /// How much the patch grows the global heap by.
+    global_heap_growth: u64,
+}
 
-            ptr.write_unaligned(val);
+struct PatchHelper<'a, M: Memory> {
+    memory: &'a mut M,
+    config: &'a Config,
+    patch_set: &'a PatchSet,
+    /// The growth of the heaps allocated from the global heap, in bytes.
+    global_heap_bonus: u64,
+    /// How many times larger than vanilla each resized heap is, rounded up.
+    heap_ratios: HashMap<&'a str, u64>,
+    /// The heap sizes scaled back to fit the memory budget, replacing the configured ones.
+    scaled_sizes: HashMap<String, HeapSize>,
+    group: &'a str,
+    patches: Vec<Patch<'a>>,
+}
+
+impl<'a, M: Memory> PatchHelper<'a, M> {
+    fn new(memory: &'a mut M, config: &'a Config, patch_set: &'a PatchSet) -> PatchHelper<'a, M> {
+        Self {
+            memory,
+            config,
+            patch_set,
+            global_heap_bonus: 0,
+            heap_ratios: HashMap::new(),
+            scaled_sizes: HashMap::new(),
+            group: "",
+            patches: Vec::new(),
         }
+    }
 
-        Ok(())
+    /// Starts a new patch group, every following patch is staged as a part of it.
+    fn group(&mut self, name: &'a str) {
+        self.group = name;
     }
 
-    fn add_u32(&mut self, offset: usize, val: u32) -> WindowsResult<()> {
-        Self::set_rwe_memory_u32(self.base_addr + offset)?;
+    /// Reads the vanilla size of every heap resized by the enabled patches, and of the
+    /// global heap.
+    ///
+    /// Rejects any heap whose configured size does not fit in its 32-bit size operand, whatever
+    /// the budget `exceeded` action, as scaling every heap to fit one would hide the mistake.
+    fn heap_bases(&self) -> Result<(Vec<HeapBase>, Option<u32>), PatchError> {
+        let mut heaps = Vec::<HeapBase>::new();
+        let mut global = None;
+
+        for patch in &self.patch_set.patches {
+            if !self.patch_set.group_enabled(&patch.group, self.config) {
+                continue;
+            }
+
+            match patch.op {
+                PatchOp::Heap => {
+                    let heap = patch.heap.as_deref().unwrap_or_default();
+
+                    match heaps.iter_mut().find(|base| base.heap == heap) {
+                        Some(base) => base.global_heap |= patch.global_heap,
+                        None => {
+                            let base = self.memory.read_u32(self.resolve(patch.site())?)?;
+
+                            Self::checked_u32(patch, Some(heap), self.heap_size(heap).size(base))?;
+
+                            heaps.push(HeapBase {
+                                heap: heap.to_owned(),
+                                base,
+                                global_heap: patch.global_heap,
+                            });
+                        }
+                    }
+                }
+                PatchOp::GlobalHeap if global.is_none() => {
+                    global = Some((patch, self.memory.read_u32(self.resolve(patch.site())?)?));
+                }
+                _ => {}
+            }
+        }
+
+        if let Some((patch, base)) = global {
+            let growth = heaps
+                .iter()
+                .filter(|heap| heap.global_heap)
+                .map(|heap| self.heap_size(&heap.heap).size(heap.base) - u64::from(heap.base))
+                .sum::<u64>();
 
-        unsafe {
-            let ptr = (self.base_addr + offset) as *mut u32;
+            let size = self
+                .heap_size("global")
+                .size(base)
+                .max(u64::from(base) + growth);
 
-            ptr.write_unaligned(ptr.read_unaligned().saturating_add(val));
+            Self::checked_u32(patch, Some("global"), size)?;
         }
 
-        Ok(())
+        Ok((heaps, global.map(|(_, base)| base)))
+    }
+
+    /// The size of `heap`, as scaled to fit the budget or as configured.
+    fn heap_size(&self, heap: &str) -> HeapSize {
+        self.scaled_sizes
+            .get(heap)
+            .copied()
+            .or_else(|| self.config.heap_sizes.get(heap))
+            .unwrap_or(HeapSize::Multiplier(1.0))
+    }
+
+    /// Stages the write described by `patch`.
+    fn place(&mut self, patch: &'a PatchDef) -> Result<(), PatchError> {
+        let offset = self.resolve(patch.site())?;
+
+        let expected = match (&patch.expected, &patch.expected_bytes) {
+            (Some(expected), _) => Expected::Value(self.eval_u32(patch, expected)?),
+            (None, Some(pattern)) => Expected::Bytes(pattern.clone()),
+            (None, None) => Expected::Any,
+        };
+
+        let val = match &patch.value {
+            Some(value) => self.eval_u32(patch, value)?,
+            None => 0,
+        };
+
+        match patch.op {
+            PatchOp::Set => self.set_u32(patch, offset, expected, val),
+            PatchOp::Add => self.add_u32(patch, offset, expected, val),
+            PatchOp::Mul => self.mul_u32(patch, offset, expected, val),
+            PatchOp::Heap => self.heap_u32(patch, offset, expected),
+            PatchOp::Nop => self.nop(patch, offset, expected),
+            PatchOp::GlobalHeap => self.set_global_heap_u32(patch, offset, expected),
+        }
+    }
+
+    fn eval_u32(&self, patch: &PatchDef, expr: &Expr) -> Result<u32, PatchError> {
+        self.patch_set
+            .eval(expr, &|name| self.lookup(name))
+            .and_then(|val| {
+                u32::try_from(val).map_err(|_| ExprError::Overflow(expr.source().to_owned()))
+            })
+            .map_err(|err| PatchError::Expression {
+                patch: patch.name.clone(),
+                err,
+            })
     }
 
-    fn mul_u32(&mut self, offset: usize, val: u32, add_to_global_heap: bool) -> WindowsResult<()> {
-        Self::set_rwe_memory_u32(self.base_addr + offset)?;
+    /// Resolves `limits.<name>` to the configured limit, and `heap.<name>` to how many times
+    /// larger than vanilla the heap is, rounded up.
+    ///
+    /// Absolute heap sizes are only known relative to vanilla once their heap has been resized.
+    fn lookup(&self, name: &str) -> Option<u64> {
+        if let Some(limit) = name.strip_prefix("limits.") {
+            return self.config.limits.get(limit).map(u64::from);
+        }
+
+        let heap = name.strip_prefix("heap.")?;
+
+        if let Some(&ratio) = self.heap_ratios.get(heap) {
+            return Some(ratio);
+        }
+
+        let size = self
+            .scaled_sizes
+            .get(heap)
+            .copied()
+            .or_else(|| self.config.heap_sizes.get(heap))?;
 
-        unsafe {
-            let ptr = (self.base_addr + offset) as *mut u32;
+        match size {
+            HeapSize::Multiplier(multiplier) => Some(multiplier.ceil() as u64),
+            HeapSize::Bytes(_) => None,
+        }
+    }
 
-            let base = ptr.read_unaligned();
+    fn resolve(&self, site: Site) -> Result<usize, PatchError> {
+        match site {
+            Site::Rva(rva) => Ok(rva),
+            Site::Signature { pattern, operand } => {
+                let (base_rva, bytes) = self.memory.bytes()?;
 
-            if add_to_global_heap {
-                self.global_heap_bonus = self
-                    .global_heap_bonus
-                    .saturating_add(base.saturating_mul(val - 1));
+                match pattern.find_unique(bytes) {
+                    Ok(offset) => Ok(base_rva + offset + operand),
+                    Err(err) => Err(PatchError::Scan { pattern, err }),
+                }
             }
+        }
+    }
 
-            ptr.write_unaligned(base.saturating_mul(val));
+    /// Checks that the site lies in the expected section of the image and stages the write.
+    ///
+    /// Memories without PE headers are only bounds checked, by reading the site.
+    fn stage(
+        &mut self,
+        patch: &'a PatchDef,
+        offset: usize,
+        expected: Expected,
+        bytes: Vec<u8>,
+    ) -> Result<(), PatchError> {
+        let len = bytes.len().max(expected.len());
+
+        if let Some(headers) = self.memory.headers() {
+            if headers
+                .section_containing(offset, len)
+                .is_none_or(|section| section.name != patch.section)
+            {
+                return Err(PatchError::Section {
+                    patch: patch.name.clone(),
+                    offset,
+                    len,
+                    section: patch.section.clone(),
+                });
+            }
         }
 
+        self.memory.read(offset, &mut vec![0; len])?;
+
+        self.patches.push(Patch {
+            group: self.group,
+            name: &patch.name,
+            offset,
+            expected,
+            bytes,
+            global_heap_growth: 0,
+        });
+
         Ok(())
     }
 
-    fn set_global_heap_u32(&mut self, offset: usize) -> WindowsResult<()> {
-        Self::set_rwe_memory_u32(self.base_addr + offset)?;
+    fn set_u32(
+        &mut self,
+        patch: &'a PatchDef,
+        offset: usize,
+        expected: Expected,
+        val: u32,
+    ) -> Result<(), PatchError> {
+        self.stage(patch, offset, expected, val.to_le_bytes().to_vec())
+    }
+
+    fn add_u32(
+        &mut self,
+        patch: &'a PatchDef,
+        offset: usize,
+        expected: Expected,
+        val: u32,
+    ) -> Result<(), PatchError> {
+        let base = self.memory.read_u32(offset)?;
+
+        let val = Self::checked_u32(patch, None, u64::from(base) + u64::from(val))?;
 
-        unsafe {
-            let ptr = (self.base_addr + offset) as *mut u32;
+        self.set_u32(patch, offset, expected, val)
+    }
 
-            let base = ptr.read_unaligned();
+    fn mul_u32(
+        &mut self,
+        patch: &'a PatchDef,
+        offset: usize,
+        expected: Expected,
+        val: u32,
+    ) -> Result<(), PatchError> {
+        let base = self.memory.read_u32(offset)?;
 
-            let with_mul = base.saturating_mul(self.config.heap_sizes.global);
-            let with_add = base.saturating_add(self.global_heap_bonus);
+        let val = Self::checked_u32(patch, None, u64::from(base) * u64::from(val))?;
 
-            ptr.write_unaligned(with_mul.max(with_add));
+        self.set_u32(patch, offset, expected, val)?;
+
+        if patch.global_heap {
+            self.grow_global_heap(u64::from(val.saturating_sub(base)));
         }
 
         Ok(())
     }
 
-    fn patch_morpheme_limit(&mut self) -> WindowsResult<()> {
-        const MORPHEME_DATA_FIXED_COUNT: u32 = 0x3000;
-        const MORPHEME_DATA_ELEMENT_SIZE: u32 = 0x28;
-        const MORPHEME_DATA_HEADER_SIZE: u32 = 0x28;
+    /// Resizes the heap size operand from its vanilla value, as configured for the heap of `patch`.
+    fn heap_u32(
+        &mut self,
+        patch: &'a PatchDef,
+        offset: usize,
+        expected: Expected,
+    ) -> Result<(), PatchError> {
+        let heap = patch.heap.as_deref().unwrap_or_default();
+
+        let size = self.heap_size(heap);
 
-        let morpheme_data_new_count =
-            MORPHEME_DATA_FIXED_COUNT.saturating_mul(self.config.heap_sizes.morpheme);
+        let base = self.memory.read_u32(offset)?;
+        let val = Self::checked_u32(patch, Some(heap), size.size(base))?;
 
-        self.set_u32(0x5f4f38 + 2, morpheme_data_new_count)?;
+        self.heap_ratios
+            .insert(heap, u64::from(val.div_ceil(base.max(1))));
 
-        let morpheme_data_total_size = MORPHEME_DATA_ELEMENT_SIZE
-            .saturating_mul(morpheme_data_new_count)
-            .saturating_add(MORPHEME_DATA_HEADER_SIZE);
+        self.set_u32(patch, offset, expected, val)?;
 
-        self.set_u32(0x5f4ef2 + 1, morpheme_data_total_size)?;
-        self.set_u32(0x5f4f43 + 5, morpheme_data_total_size)?;
+        if patch.global_heap {
+            self.grow_global_heap(u64::from(val - base));
+        }
 
         Ok(())
     }
 
-    fn patch_character_resource_limit(&mut self) -> WindowsResult<()> {
-        if !self.config.patch_character_limit {
-            return Ok(());
+    fn set_global_heap_u32(
+        &mut self,
+        patch: &'a PatchDef,
+        offset: usize,
+        expected: Expected,
+    ) -> Result<(), PatchError> {
+        let base = self.memory.read_u32(offset)?;
+
+        let with_size = self.heap_size("global").size(base);
+        let with_add = u64::from(base) + self.global_heap_bonus;
+
+        let val = Self::checked_u32(patch, Some("global"), with_size.max(with_add))?;
+
+        self.set_u32(patch, offset, expected, val)
+    }
+
+    /// Adds the growth of the heap staged last to the global heap.
+    fn grow_global_heap(&mut self, growth: u64) {
+        self.global_heap_bonus += growth;
+
+        if let Some(staged) = self.patches.last_mut() {
+            staged.global_heap_growth = growth;
         }
+    }
 
-        /*
-           Patching the code accessing the struct below to increase how
-           many character types can be loaded by the game at once:
-
-           struct ResObjectHolder {
-               DLAllocator* allocator;
-               DLFixedVector<ChrResModelObject*, 32> model_res_objects;
-               DLFixedVector<ChrResMorphemeObject*, 32> morpheme_res_objects;
-               DLFixedVector<ChrResSoundObject*, 32> sound_res_objects;
-               DLFixedVector<ChrResTimeActObject*, 32> tae_res_objects;
-           };
-
-           The default limit is 32. After more than 32 characters are loaded, no new character
-           resources can be inserted, and character loading will never finish. This leads to
-           missing enemies and infinite loading screens.
-
-           The layout of a DLFixedVector is:
-
-           template <typename T, size_t Capacity>
-           struct DLFixedVector {
-               std::byte buffer[sizeof(T) * Capacity + alignof(T)];
-               size_t size;
-           };
-
-           Note that the elements inside the buffer are manually aligned, so extra space is needed
-           (at most the alignment of T).
-
-           Any code accessing the fixed vector is compiled with the capacity and overall structure
-           size, so *everything* in accessing code needs to be patched to increase the capacity.
-        */
-
-        const DLALLOCATOR_BASE_SIZE: u32 = 8;
-        const DLFIXEDVECTOR_ELEMENT_SIZE: u32 = 8;
-        const DLFIXEDVECTOR_BASE_CAPACITY: u32 = 32;
-
-        // Patch the capacity from 32 to 1024, should realistically be enough:
-        const DLFIXEDVECTOR_NEW_CAPACITY: u32 = DLFIXEDVECTOR_BASE_CAPACITY * 32;
-
-        // sizeof(T) * Capacity + alignof(T) + sizeof(size_t)
-        const DLFIXEDVECTOR_NEW_SIZE: u32 =
-            DLFIXEDVECTOR_ELEMENT_SIZE * DLFIXEDVECTOR_NEW_CAPACITY + 8 + 8;
-
-        // Total patched structure size
-        const RES_OBJECT_HOLDER_SIZE: u32 = DLALLOCATOR_BASE_SIZE + DLFIXEDVECTOR_NEW_SIZE * 4;
-
-        // Offsets of each fixed vector in `ResObjectHolder`
-        const DLFIXEDVECTOR_0_OFFSET: u32 = DLALLOCATOR_BASE_SIZE;
-        const DLFIXEDVECTOR_1_OFFSET: u32 = DLFIXEDVECTOR_0_OFFSET + DLFIXEDVECTOR_NEW_SIZE;
-        const DLFIXEDVECTOR_2_OFFSET: u32 = DLFIXEDVECTOR_1_OFFSET + DLFIXEDVECTOR_NEW_SIZE;
-        const DLFIXEDVECTOR_3_OFFSET: u32 = DLFIXEDVECTOR_2_OFFSET + DLFIXEDVECTOR_NEW_SIZE;
-
-        // Offset of `size` field in `DLFixedVector<T, N>`
-        const DLFIXEDVECTOR_SIZE_OFFSET: u32 = DLFIXEDVECTOR_NEW_SIZE - 8;
-
-        // Offsets of each fixed vector's `size` field in `ResObjectHolder`
-        const DLFIXEDVECTOR_0_SIZE_OFFSET: u32 = DLALLOCATOR_BASE_SIZE + DLFIXEDVECTOR_SIZE_OFFSET;
-        const DLFIXEDVECTOR_1_SIZE_OFFSET: u32 =
-            DLFIXEDVECTOR_0_SIZE_OFFSET + DLFIXEDVECTOR_NEW_SIZE;
-        const DLFIXEDVECTOR_2_SIZE_OFFSET: u32 =
-            DLFIXEDVECTOR_1_SIZE_OFFSET + DLFIXEDVECTOR_NEW_SIZE;
-        const DLFIXEDVECTOR_3_SIZE_OFFSET: u32 =
-            DLFIXEDVECTOR_2_SIZE_OFFSET + DLFIXEDVECTOR_NEW_SIZE;
-
-        // DarkSoulsII.exe+0x165c80:
-        self.set_u32(0x165c85 + 3, DLFIXEDVECTOR_0_SIZE_OFFSET)?;
-        self.set_u32(0x165c8c + 3, DLFIXEDVECTOR_1_SIZE_OFFSET)?;
-        self.set_u32(0x165c93 + 3, DLFIXEDVECTOR_2_SIZE_OFFSET)?;
-        self.set_u32(0x165c9a + 3, DLFIXEDVECTOR_3_SIZE_OFFSET)?;
-
-        // DarkSoulsII.exe+0x166370:
-        self.set_u32(0x1663ad + 3, DLFIXEDVECTOR_NEW_SIZE)?;
-        self.set_u32(0x1663b7 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x166402 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x166419 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x166470 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x166492 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x1664b8 + 3, DLFIXEDVECTOR_NEW_SIZE)?;
-        self.set_u32(0x1664c3 + 3, DLFIXEDVECTOR_NEW_SIZE)?;
-
-        // DarkSoulsII.exe+0x166560:
-        self.set_u32(0x166567 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-
-        // DarkSoulsII.exe+0x1665c0:
-        self.set_u32(0x1665c0 + 3, DLFIXEDVECTOR_1_OFFSET)?;
-        self.set_u32(0x1665ca + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-
-        // DarkSoulsII.exe+0x166620:
-        self.set_u32(0x166620 + 3, DLFIXEDVECTOR_1_OFFSET)?;
-        self.set_u32(0x16662a + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-
-        // DarkSoulsII.exe+0x166680:
-        self.set_u32(0x166687 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-
-        // DarkSoulsII.exe+0x1666e0:
-        self.set_u32(0x1666e0 + 3, DLFIXEDVECTOR_3_OFFSET)?;
-        self.set_u32(0x1666ea + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-
-        // DarkSoulsII.exe+0x1671d0:
-        self.set_u32(0x167279 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x167289 + 3, DLFIXEDVECTOR_NEW_SIZE)?;
-        self.set_u32(0x1673eb + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x167432 + 3, DLFIXEDVECTOR_NEW_SIZE)?;
-        self.set_u32(0x1674c0 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x1674d0 + 3, DLFIXEDVECTOR_NEW_SIZE)?;
-        self.set_u32(0x167540 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x16755e + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x167585 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x167592 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x1675ac + 3, DLFIXEDVECTOR_NEW_SIZE)?;
-
-        // DarkSoulsII.exe+0x167660:
-        self.set_u32(0x16766e + 3, DLFIXEDVECTOR_NEW_SIZE)?;
-        self.set_u32(0x16767b + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-
-        // DarkSoulsII.exe+0x167780:
-        self.set_u32(0x1677a4 + 3, DLFIXEDVECTOR_NEW_SIZE)?;
-        self.set_u32(0x1677b1 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x1677e5 + 4, DLFIXEDVECTOR_NEW_SIZE)?;
-        self.set_u32(0x16793d + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0x167951 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        // Neutralize size overflow check exceptions
-        self.set_u32(0x1677ee + 2, 0)?;
-        self.set_u32(0x167947, 0xF9909090)?;
-
-        // DarkSoulsII.exe+0x1679c0:
-        self.set_u32(0x1679ca + 3, DLFIXEDVECTOR_NEW_SIZE)?;
-        self.set_u32(0x1679d7 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-
-        // DarkSoulsII.exe+0x350e00:
-        self.set_u32(0x350e16 + 1, RES_OBJECT_HOLDER_SIZE)?;
+    /// Rejects the new value of `patch` if it does not fit in its 32-bit operand, instead of
+    /// writing a truncated or saturated size.
+    fn checked_u32(patch: &PatchDef, heap: Option<&str>, val: u64) -> Result<u32, PatchError> {
+        u32::try_from(val).map_err(|_| PatchError::Overflow {
+            patch: patch.name.clone(),
+            heap: heap.map(str::to_owned),
+            val,
+        })
+    }
 
-        Ok(())
+    fn nop(
+        &mut self,
+        patch: &'a PatchDef,
+        offset: usize,
+        expected: Expected,
+    ) -> Result<(), PatchError> {
+        const NOP: u8 = 0x90;
+
+        let len = patch.len.unwrap_or_default();
+
+        self.stage(patch, offset, expected, vec![NOP; len])
     }
 
-    fn patch_soundbank_limit(&mut self) -> WindowsResult<()> {
-        if !self.config.patch_soundbank_limit {
-            return Ok(());
+    /// Checks every staged site against its expected original value
+    /// and only writes the patches if all of them match.
+    ///
+    /// Writing is transactional: if any site of a group fails to be written,
+    /// every site written so far is restored to its original value. This includes
+    /// the groups that were fully applied, as the global heap size accounts for
+    /// all of the heaps, and the game is left running vanilla.
+    fn apply(self) -> Result<Vec<SiteReport>, PatchError> {
+        self.verify()?;
+
+        if self.config.dry_run {
+            return self.dry_run();
         }
 
-        /*
-            Look above at `PatchHelper::patch_character_resource_limit` for detailed layout information.
+        let mut originals = Vec::with_capacity(self.patches.len());
+
+        for patch in &self.patches {
+            if let Err(err) = Self::write_site(self.memory, patch, &mut originals) {
+                return match Self::rollback(self.memory, &originals) {
+                    Ok(()) => Err(PatchError::Write {
+                        group: patch.group.to_owned(),
+                        err,
+                    }),
+                    Err(rollback_err) => Err(PatchError::Rollback {
+                        group: patch.group.to_owned(),
+                        err,
+                        rollback_err,
+                    }),
+                };
+            }
+        }
 
-            struct RegisteredBankHolder {
-               uint32_t total_count;
-               DLFixedVector<RegisteredBank, 48> registered_banks;
-            };
+        let sites = self
+            .patches
+            .into_iter()
+            .zip(originals)
+            .map(|(patch, (_, original))| SiteReport {
+                group: patch.group.to_owned(),
+                name: patch.name.to_owned(),
+                offset: patch.offset,
+                original,
+                checked: !matches!(patch.expected, Expected::Any),
+                patched: patch.bytes,
+                global_heap_growth: patch.global_heap_growth,
+            })
+            .collect();
 
-            sizeof(RegisteredBank) == 632, alignof(RegisteredBank) == 8
-        */
+        Ok(sites)
+    }
 
-        const DLFIXEDVECTOR_ELEMENT_SIZE: u32 = 632;
+    /// Reports the staged sites as `apply` would, without making them writable or writing them.
+    fn dry_run(self) -> Result<Vec<SiteReport>, PatchError> {
+        let mut sites = Vec::with_capacity(self.patches.len());
 
-        // More than the total number of all soundbanks in the /sound directory
-        const DLFIXEDVECTOR_NEW_CAPACITY: u32 = 513;
+        for patch in self.patches {
+            let mut original = vec![0; patch.bytes.len()];
 
-        // sizeof(T) * Capacity + alignof(T) + sizeof(size_t)
-        const DLFIXEDVECTOR_NEW_SIZE: u32 =
-            DLFIXEDVECTOR_ELEMENT_SIZE * DLFIXEDVECTOR_NEW_CAPACITY + 8 + 8;
+            self.memory.read(patch.offset, &mut original)?;
+
+            sites.push(SiteReport {
+                group: patch.group.to_owned(),
+                name: patch.name.to_owned(),
+                offset: patch.offset,
+                original,
+                checked: !matches!(patch.expected, Expected::Any),
+                patched: patch.bytes,
+                global_heap_growth: patch.global_heap_growth,
+            });
+        }
 
-        // Total patched structure size
-        const REGISTERED_BANK_HOLDER_SIZE: u32 = 8 + DLFIXEDVECTOR_NEW_SIZE;
+        Ok(sites)
+    }
 
-        // Offset of `size` field in `DLFixedVector<T, N>`
-        const DLFIXEDVECTOR_SIZE_OFFSET: u32 = DLFIXEDVECTOR_NEW_SIZE - 8;
+    fn verify(&self) -> Result<(), PatchError> {
+        let mut mismatches = Vec::new();
 
-        // Offsets of each fixed vector's `size` field in `ResObjectHolder`
-        const DLFIXEDVECTOR_0_SIZE_OFFSET: u32 = 8 + DLFIXEDVECTOR_SIZE_OFFSET;
+        for patch in &self.patches {
+            if let Expected::Any = patch.expected {
+                continue;
+            }
 
-        // DarkSoulsII.exe+0xb074d0:
-        self.set_u32(0xb07741 + 1, REGISTERED_BANK_HOLDER_SIZE)?;
+            let mut found = vec![0; patch.expected.len()];
 
-        // DarkSoulsII.exe+0xb57df0:
-        self.set_u32(0xb57dfa + 3, DLFIXEDVECTOR_0_SIZE_OFFSET)?;
+            self.memory.read(patch.offset, &mut found)?;
 
-        // DarkSoulsII.exe+0xb57d70:
-        self.set_u32(0xb57d74 + 3, DLFIXEDVECTOR_0_SIZE_OFFSET)?;
+            if !patch.expected.matches(&found) {
+                mismatches.push(SiteMismatch {
+                    name: patch.name.to_owned(),
+                    offset: patch.offset,
+                    expected: patch.expected.to_string(),
+                    found,
+                });
+            }
+        }
 
-        // DarkSoulsII.exe+0xb580f0:
-        self.set_u32(0xb58113 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(PatchError::Mismatch(mismatches))
+        }
+    }
 
-        // DarkSoulsII.exe+0xb58240:
-        self.set_u32(0xb5825d, 0xF9909090)?;
-        self.set_u32(0xb5825d + 4, 0x90909090)?;
+    fn write_site(
+        memory: &mut M,
+        patch: &Patch,
+        originals: &mut Vec<(usize, Vec<u8>)>,
+    ) -> Result<(), MemoryError> {
+        let mut original = vec![0; patch.bytes.len()];
 
-        // DarkSoulsII.exe+0xb583a0:
-        self.set_u32(0xb583c6 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0xb58521 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0xb58549 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0xb58575 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0xb5857c + 3, DLFIXEDVECTOR_0_SIZE_OFFSET)?;
+        memory.read(patch.offset, &mut original)?;
+        memory.protect(patch.offset, patch.bytes.len())?;
 
-        // DarkSoulsII.exe+0xb58650:
-        self.set_u32(0xb58654 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
-        self.set_u32(0xb5865e, 0xF9909090)?;
-        self.set_u32(0xb58667 + 3, DLFIXEDVECTOR_SIZE_OFFSET)?;
+        // Saved before writing, a partially failed write is restored as well.
+        originals.push((patch.offset, original));
 
-        Ok(())
+        memory.write(patch.offset, &patch.bytes)
     }
 
-    fn patch_map_dtor_stack(&mut self) -> WindowsResult<()> {
-        // A stack allocated array at DarkSoulsII.exe+0x40db30
-        // has a fixed size of 256 and no bounds checking, allocated on the stack.
-        // This leads to a stack overflow and a crash when a map with more than 256
-        // enemies is deloaded and destroyed.
+    /// Restores the saved original values in reverse order, so sites that
+    /// were patched more than once end up with their first original value.
+    fn rollback(memory: &mut M, originals: &[(usize, Vec<u8>)]) -> Result<(), MemoryError> {
+        let mut result = Ok(());
+
+        for (offset, original) in originals.iter().rev() {
+            if let Err(err) = memory.write(*offset, original) {
+                result = result.and(Err(err));
+            }
+        }
 
-        const STACK_ELEMENT_SIZE: u32 = 0x34;
-        const ARRAY_OLD_CAPACITY: u32 = STACK_ELEMENT_SIZE * 256;
+        result
+    }
+}
 
-        // A maximum of 1024 enemies per map.
-        const ARRAY_NEW_CAPACITY: u32 = ARRAY_OLD_CAPACITY * 4;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BufferMemory;
+
+    const PATCH_SET: &str = r#"
+version = "1.0.3.0"
+
+[[group]]
+name = "sound_heap"
+toggle = "patch_heaps.sound"
+
+[[group]]
+name = "soundbank_limit"
+toggle = "patch_soundbank_limit"
+
+[[patch]]
+group = "sound_heap"
+name = "sound heap size"
+rva = 0x1000
+op = "heap"
+heap = "sound"
+expected = 0x100000
+global_heap = true
+
+[[patch]]
+group = "soundbank_limit"
+name = "soundbank count"
+rva = 0x1008
+op = "set"
+value = "limits.soundbank"
+expected = 48
+
+[[patch]]
+group = "soundbank_limit"
+name = "soundbank holder size"
+rva = 0x100c
+op = "add"
+value = "(limits.soundbank - 48) * 632"
+unchecked = "not recorded"
+
+[[patch]]
+group = "soundbank_limit"
+name = "soundbank limit check"
+signature = "83 F8 30 7D ??"
+operand = 3
+op = "nop"
+len = 2
+expected_bytes = "7D ??"
+"#;
+
+    fn patch_set() -> PatchSet {
+        PATCH_SET.parse().unwrap()
+    }
 
-        // How many bytes to increase the size of the stack by.
-        const STACK_GROWTH: u32 = ARRAY_NEW_CAPACITY - ARRAY_OLD_CAPACITY;
+    fn config(source: &str) -> Config {
+        Config::parse(source).unwrap().0
+    }
 
-        // DarkSoulsII.exe+0x40db30:
-        self.add_u32(0x40db34 + 1, STACK_GROWTH)?;
-        self.add_u32(0x40db4b + 4, STACK_GROWTH)?;
-        self.add_u32(0x40db8e + 4, STACK_GROWTH)?;
-        self.add_u32(0x40dba9 + 2, STACK_GROWTH)?;
-        self.add_u32(0x40dc07 + 4, STACK_GROWTH)?;
-        self.add_u32(0x40dc18 + 4, STACK_GROWTH)?;
-        self.add_u32(0x40dc26 + 4, STACK_GROWTH)?;
-        self.add_u32(0x40dc5c + 4, STACK_GROWTH)?;
-        self.add_u32(0x40dc64 + 4, STACK_GROWTH)?;
-        self.add_u32(0x40dc82 + 4, STACK_GROWTH)?;
-        self.add_u32(0x40dc92 + 3, STACK_GROWTH)?;
+    fn memory() -> BufferMemory {
+        let mut bytes = vec![0xCC; 0x20];
 
-        Ok(())
+        bytes[0x00..0x04].copy_from_slice(&0x100000u32.to_le_bytes());
+        bytes[0x08..0x0c].copy_from_slice(&48u32.to_le_bytes());
+        bytes[0x0c..0x10].copy_from_slice(&0x100u32.to_le_bytes());
+        bytes[0x10..0x15].copy_from_slice(&[0x83, 0xF8, 0x30, 0x7D, 0x05]);
+
+        BufferMemory::new(0x1000, bytes)
     }
 
-    fn set_rwe_memory_u32(addr: usize) -> WindowsResult<()> {
-        unsafe {
-            VirtualProtect(
-                addr as _,
-                4,
-                PAGE_EXECUTE_READWRITE,
-                &mut PAGE_PROTECTION_FLAGS::default(),
-            )
+    const CONFIG: &str = "heap_size_multiplier = 1\n[heap_sizes]\nsound = 3\n\
+                          [limits]\nsoundbank = 512\n";
+
+    /// Fails to write at `fail_at`, only once unless `fail_rollback`.
+    struct FailingMemory {
+        memory: BufferMemory,
+        fail_at: Option<usize>,
+        fail_rollback: bool,
+    }
+
+    impl Memory for FailingMemory {
+        fn read(&self, rva: usize, buf: &mut [u8]) -> Result<(), MemoryError> {
+            self.memory.read(rva, buf)
+        }
+
+        fn write(&mut self, rva: usize, bytes: &[u8]) -> Result<(), MemoryError> {
+            if self.fail_at == Some(rva) {
+                if !self.fail_rollback {
+                    self.fail_at = None;
+                }
+
+                return Err(MemoryError::OutOfBounds {
+                    rva,
+                    len: bytes.len(),
+                });
+            }
+
+            self.memory.write(rva, bytes)
         }
+
+        fn protect(&mut self, rva: usize, len: usize) -> Result<(), MemoryError> {
+            self.memory.protect(rva, len)
+        }
+
+        fn bytes(&self) -> Result<(usize, &[u8]), MemoryError> {
+            self.memory.bytes()
+        }
+    }
+
+    #[test]
+    fn places_every_enabled_patch() {
+        let mut memory = memory();
+
+        let report = place_all(&mut memory, &config(CONFIG), &patch_set()).unwrap();
+
+        assert_eq!(memory.read_u32(0x1000).unwrap(), 0x300000);
+        assert_eq!(memory.read_u32(0x1008).unwrap(), 512);
+        assert_eq!(memory.read_u32(0x100c).unwrap(), 0x100 + 464 * 632);
+        assert_eq!(
+            &memory.as_bytes()[0x10..0x15],
+            [0x83, 0xF8, 0x30, 0x90, 0x90]
+        );
+
+        let sites = report
+            .sites
+            .iter()
+            .map(|site| (site.offset, site.checked))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            sites,
+            [
+                (0x1000, true),
+                (0x1008, true),
+                (0x100c, false),
+                (0x1013, true)
+            ]
+        );
+        assert_eq!(report.sites[3].original, [0x7D, 0x05]);
+        assert_eq!(report.sites[3].patched, [0x90, 0x90]);
+        assert!(report
+            .groups
+            .iter()
+            .all(|(_, state)| *state == GroupState::Enabled));
+    }
+
+    #[test]
+    fn skips_disabled_groups() {
+        let mut memory = memory();
+
+        let report = place_all(
+            &mut memory,
+            &config(&format!("patch_soundbank_limit = false\n{CONFIG}")),
+            &patch_set(),
+        )
+        .unwrap();
+
+        assert_eq!(report.sites.len(), 1);
+        assert_eq!(memory.read_u32(0x1008).unwrap(), 48);
+        assert_eq!(
+            report.groups[1],
+            (
+                "soundbank_limit".to_owned(),
+                GroupState::Disabled("patch_soundbank_limit".to_owned())
+            )
+        );
+    }
+
+    #[test]
+    fn writes_nothing_if_a_site_does_not_match() {
+        let mut memory = memory();
+
+        memory.write_u32(0x1008, 64).unwrap();
+
+        let original = memory.as_bytes().to_vec();
+
+        let err = place_all(&mut memory, &config(CONFIG), &patch_set())
+            .err()
+            .unwrap();
+
+        let PatchError::Mismatch(mismatches) = &err else {
+            panic!("expected a mismatch, got {err}");
+        };
+
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].offset, 0x1008);
+        assert_eq!(mismatches[0].found, 64u32.to_le_bytes());
+        assert_eq!(memory.as_bytes(), original);
+    }
+
+    #[test]
+    fn rolls_back_when_a_write_fails() {
+        let original = memory().into_bytes();
+
+        let mut memory = FailingMemory {
+            memory: memory(),
+            fail_at: Some(0x100c),
+            fail_rollback: false,
+        };
+
+        let err = place_all(&mut memory, &config(CONFIG), &patch_set())
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            &err,
+            PatchError::Write { group, .. } if group == "soundbank_limit"
+        ));
+        assert_eq!(memory.memory.as_bytes(), original);
+    }
+
+    #[test]
+    fn reports_failed_rollback() {
+        let mut memory = FailingMemory {
+            memory: memory(),
+            fail_at: Some(0x100c),
+            fail_rollback: true,
+        };
+
+        let err = place_all(&mut memory, &config(CONFIG), &patch_set())
+            .err()
+            .unwrap();
+
+        assert!(matches!(err, PatchError::Rollback { .. }));
+        // Restored up to the failing site.
+        assert_eq!(memory.memory.read_u32(0x1000).unwrap(), 0x100000);
+        assert_eq!(memory.memory.read_u32(0x1008).unwrap(), 48);
+    }
+
+    #[test]
+    fn rejects_heaps_over_4_gib() {
+        let mut memory = memory();
+
+        let original = memory.as_bytes().to_vec();
+
+        // Scaling to the budget is the default, it must not shrink the heap to fit either.
+        let err = place_all(
+            &mut memory,
+            &config("[heap_sizes]\nsound = \"8GiB\"\n[budget]\nmax_total = \"1GiB\"\n"),
+            &patch_set(),
+        )
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            &err,
+            PatchError::Overflow { heap: Some(heap), val, .. }
+                if heap == "sound" && *val == 8 << 30
+        ));
+        assert_eq!(memory.as_bytes(), original);
+    }
+
+    #[test]
+    fn rejects_ambiguous_signatures() {
+        let mut memory = memory();
+
+        memory
+            .write(0x1018, &[0x83, 0xF8, 0x30, 0x7D, 0x00])
+            .unwrap();
+
+        let err = place_all(&mut memory, &config(CONFIG), &patch_set())
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            err,
+            PatchError::Scan {
+                err: ScanError::NotUnique(2),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn dry_run_reports_without_writing() {
+        let mut memory = memory();
+
+        let original = memory.as_bytes().to_vec();
+
+        let report = place_all(
+            &mut memory,
+            &config(&format!("dry_run = true\n{CONFIG}")),
+            &patch_set(),
+        )
+        .unwrap();
+
+        assert_eq!(memory.as_bytes(), original);
+        assert_eq!(
+            SiteTable(&report.sites).to_string(),
+            "\
+site                    group            name                   old         new         global heap
+DarkSoulsII.exe+0x1000  sound_heap       sound heap size        0x00100000  0x00300000  +0x200000
+DarkSoulsII.exe+0x1008  soundbank_limit  soundbank count        0x00000030  0x00000200
+DarkSoulsII.exe+0x100c  soundbank_limit  soundbank holder size  0x00000100  0x00047a80
+DarkSoulsII.exe+0x1013  soundbank_limit  soundbank limit check  7D 05       90 90"
+        );
     }
 }
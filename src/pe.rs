@@ -0,0 +1,164 @@
+use std::fmt;
+
+/// The parts of the PE headers needed to map RVAs to file offsets and to check
+/// which section they belong to.
+///
+/// Parses both raw files and mapped modules, as the headers are mapped unchanged.
+pub struct PeHeaders {
+    pub image_size: u32,
+    /// The RVA of the resource directory, 0 if the image has none.
+    pub resource_rva: u32,
+    pub sections: Vec<Section>,
+}
+
+pub struct Section {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub raw_offset: u32,
+    pub raw_size: u32,
+}
+
+#[derive(Debug)]
+pub enum PeError {
+    /// The headers end before `offset`.
+    Truncated(usize),
+    InvalidSignature,
+    /// Only PE32+ (64-bit) images are supported.
+    UnsupportedFormat(u16),
+}
+
+impl fmt::Display for PeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated(offset) => write!(f, "PE headers truncated at {offset:#x}"),
+            Self::InvalidSignature => write!(f, "invalid DOS or PE signature"),
+            Self::UnsupportedFormat(magic) => {
+                write!(f, "unsupported optional header magic {magic:#x}")
+            }
+        }
+    }
+}
+
+impl PeHeaders {
+    pub fn parse(bytes: &[u8]) -> Result<Self, PeError> {
+        const IMAGE_DOS_SIGNATURE: u16 = 0x5A4D;
+        const IMAGE_NT_SIGNATURE: u32 = 0x4550;
+        const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20B;
+        const IMAGE_SIZEOF_FILE_HEADER: usize = 20;
+        const IMAGE_SIZEOF_SECTION_HEADER: usize = 40;
+
+        if read_u16(bytes, 0)? != IMAGE_DOS_SIGNATURE {
+            return Err(PeError::InvalidSignature);
+        }
+
+        // IMAGE_DOS_HEADER::e_lfanew
+        let nt_headers = read_u32(bytes, 0x3c)? as usize;
+
+        if read_u32(bytes, nt_headers)? != IMAGE_NT_SIGNATURE {
+            return Err(PeError::InvalidSignature);
+        }
+
+        let file_header = nt_headers + 4;
+        let section_count = read_u16(bytes, file_header + 2)? as usize;
+        let optional_header_size = read_u16(bytes, file_header + 16)? as usize;
+
+        let optional_header = file_header + IMAGE_SIZEOF_FILE_HEADER;
+
+        match read_u16(bytes, optional_header)? {
+            IMAGE_NT_OPTIONAL_HDR64_MAGIC => {}
+            magic => return Err(PeError::UnsupportedFormat(magic)),
+        }
+
+        let image_size = read_u32(bytes, optional_header + 0x38)?;
+
+        // IMAGE_OPTIONAL_HEADER64::NumberOfRvaAndSizes, DataDirectory[IMAGE_DIRECTORY_ENTRY_RESOURCE]
+        let resource_rva = match read_u32(bytes, optional_header + 108)? {
+            0..=2 => 0,
+            _ => read_u32(bytes, optional_header + 128)?,
+        };
+
+        let section_headers = optional_header + optional_header_size;
+
+        let sections = (0..section_count)
+            .map(|i| {
+                let header = section_headers + i * IMAGE_SIZEOF_SECTION_HEADER;
+
+                let name = bytes
+                    .get(header..header + 8)
+                    .ok_or(PeError::Truncated(header))?;
+
+                Ok(Section {
+                    name: String::from_utf8_lossy(name)
+                        .trim_end_matches('\0')
+                        .to_owned(),
+                    virtual_size: read_u32(bytes, header + 8)?,
+                    virtual_address: read_u32(bytes, header + 12)?,
+                    raw_size: read_u32(bytes, header + 16)?,
+                    raw_offset: read_u32(bytes, header + 20)?,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            image_size,
+            resource_rva,
+            sections,
+        })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    /// The section `len` bytes at `rva` lie in, if they do not cross its bounds.
+    pub fn section_containing(&self, rva: usize, len: usize) -> Option<&Section> {
+        self.sections.iter().find(|section| {
+            rva.checked_sub(section.virtual_address as usize)
+                .and_then(|start| start.checked_add(len))
+                .is_some_and(|end| end <= section.virtual_len())
+        })
+    }
+
+    /// Maps `len` bytes at `rva` to an offset into the file, if they are backed by raw data.
+    pub fn rva_to_offset(&self, rva: usize, len: usize) -> Option<usize> {
+        self.sections.iter().find_map(|section| {
+            let start = rva.checked_sub(section.virtual_address as usize)?;
+            let end = start.checked_add(len)?;
+
+            (end <= section.raw_len()).then(|| section.raw_offset as usize + start)
+        })
+    }
+}
+
+impl Section {
+    /// The size of the section once mapped.
+    pub fn virtual_len(&self) -> usize {
+        match self.virtual_size {
+            0 => self.raw_size as usize,
+            virtual_size => virtual_size as usize,
+        }
+    }
+
+    /// How many bytes of the section are backed by raw data, excluding file alignment padding.
+    pub fn raw_len(&self) -> usize {
+        match self.virtual_size {
+            0 => self.raw_size as usize,
+            virtual_size => virtual_size.min(self.raw_size) as usize,
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, PeError> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(PeError::Truncated(offset))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, PeError> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(PeError::Truncated(offset))
+}
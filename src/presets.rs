@@ -0,0 +1,41 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// A built-in set of heap sizes and limits, selected with `preset = "<name>"` in the config.
+///
+/// Every key set in the config overrides the preset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    Vanilla,
+    /// The default values, the same as not selecting a preset.
+    Default,
+    LargeMod,
+    Extreme,
+}
+
+impl Preset {
+    /// The config keys set by the preset.
+    pub fn table(self) -> toml::Table {
+        let source = match self {
+            Self::Vanilla => include_str!("../config/presets/vanilla.toml"),
+            Self::Default => "",
+            Self::LargeMod => include_str!("../config/presets/large_mod.toml"),
+            Self::Extreme => include_str!("../config/presets/extreme.toml"),
+        };
+
+        source.parse().expect("valid preset")
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Vanilla => "vanilla",
+            Self::Default => "default",
+            Self::LargeMod => "large_mod",
+            Self::Extreme => "extreme",
+        })
+    }
+}
@@ -0,0 +1,251 @@
+use std::{fmt, ops::Range, str::FromStr};
+
+use serde::{Deserialize, Deserializer};
+
+/// A byte pattern ("array of bytes" signature) with wildcard support.
+///
+/// Written as space separated hex bytes, `?` or `??` matching any byte:
+/// `"48 8D 0D ?? ?? ?? ?? E8"`.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    bytes: Vec<Option<u8>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScanError {
+    InvalidPattern(String),
+    NotFound,
+    /// A unique match is required, the pattern matched this many times.
+    NotUnique(usize),
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidPattern(pattern) => write!(f, "invalid pattern \"{pattern}\""),
+            Self::NotFound => write!(f, "pattern not found"),
+            Self::NotUnique(count) => write!(f, "pattern matched {count} times, expected once"),
+        }
+    }
+}
+
+impl Pattern {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns the offset of the only match of the pattern in `haystack`.
+    pub fn find_unique(&self, haystack: &[u8]) -> Result<usize, ScanError> {
+        let mut matches = self.find_all(haystack);
+
+        let first = matches.next().ok_or(ScanError::NotFound)?;
+
+        match matches.count() {
+            0 => Ok(first),
+            rest => Err(ScanError::NotUnique(rest + 1)),
+        }
+    }
+
+    pub fn find_all<'a>(&'a self, haystack: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        haystack
+            .windows(self.bytes.len())
+            .enumerate()
+            .filter(|(_, window)| self.matches(window))
+            .map(|(offset, _)| offset)
+    }
+
+    /// Returns the shortest pattern of at most `max_len` bytes starting at `offset` in `haystack`
+    /// that only matches there, with the bytes at `wildcards` from `offset` matching any byte.
+    pub fn unique_at(
+        haystack: &[u8],
+        offset: usize,
+        wildcards: Range<usize>,
+        max_len: usize,
+    ) -> Result<Self, ScanError> {
+        let max_len = max_len.min(haystack.len().saturating_sub(offset));
+
+        let mut candidates: Vec<usize> = (0..haystack.len()).collect();
+        let mut bytes = Vec::new();
+
+        for i in 0..max_len {
+            if wildcards.contains(&i) {
+                bytes.push(None);
+                candidates.retain(|candidate| candidate + i < haystack.len());
+                continue;
+            }
+
+            let byte = haystack[offset + i];
+
+            bytes.push(Some(byte));
+            candidates.retain(|candidate| haystack.get(candidate + i) == Some(&byte));
+
+            if candidates.len() == 1 {
+                return Ok(Self { bytes });
+            }
+        }
+
+        match candidates.len() {
+            0 => Err(ScanError::NotFound),
+            count => Err(ScanError::NotUnique(count)),
+        }
+    }
+
+    /// Whether `window` starts with bytes matching the pattern.
+    pub fn matches(&self, window: &[u8]) -> bool {
+        if window.len() < self.bytes.len() {
+            return false;
+        }
+
+        self.bytes
+            .iter()
+            .zip(window)
+            .all(|(pattern, byte)| pattern.is_none_or(|pattern| pattern == *byte))
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = ScanError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_whitespace()
+            .map(|byte| match byte {
+                "?" | "??" => Some(None),
+                _ if byte.len() == 2 && byte.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    u8::from_str_radix(byte, 16).ok().map(Some)
+                }
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .filter(|bytes| !bytes.is_empty())
+            .map(|bytes| Self { bytes })
+            .ok_or_else(|| ScanError::InvalidPattern(s.to_owned()))
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.bytes.iter().enumerate() {
+            if i != 0 {
+                f.write_str(" ")?;
+            }
+
+            match byte {
+                Some(byte) => write!(f, "{byte:02X}")?,
+                None => f.write_str("??")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> Pattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_bytes_and_wildcards() {
+        let pattern = pattern("48 8d ? ?? E8");
+
+        assert_eq!(pattern.len(), 5);
+        assert_eq!(pattern.to_string(), "48 8D ?? ?? E8");
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for s in ["", "   ", "4", "488D", "GG", "+1", "48 ???"] {
+            assert_eq!(
+                s.parse::<Pattern>(),
+                Err(ScanError::InvalidPattern(s.to_owned())),
+                "{s:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn finds_unique_match() {
+        let haystack = [0x90, 0x48, 0x8D, 0x0D, 0x11, 0x22, 0xE8, 0x90];
+
+        assert_eq!(pattern("48 8D ?? ?? ?? E8").find_unique(&haystack), Ok(1));
+    }
+
+    #[test]
+    fn reports_ambiguous_match() {
+        let haystack = [0x48, 0x8D, 0x01, 0x48, 0x8D, 0x02, 0x48, 0x8D, 0x03];
+
+        assert_eq!(
+            pattern("48 8D ??").find_unique(&haystack),
+            Err(ScanError::NotUnique(3))
+        );
+        assert_eq!(
+            pattern("48 8D ??").find_all(&haystack).collect::<Vec<_>>(),
+            [0, 3, 6]
+        );
+    }
+
+    #[test]
+    fn reports_missing_match() {
+        let haystack = [0x48, 0x8D, 0x0D];
+
+        assert_eq!(
+            pattern("48 8D 0E").find_unique(&haystack),
+            Err(ScanError::NotFound)
+        );
+        // Longer than the haystack.
+        assert_eq!(
+            pattern("48 8D 0D E8").find_unique(&haystack),
+            Err(ScanError::NotFound)
+        );
+    }
+
+    #[test]
+    fn finds_shortest_unique_pattern() {
+        let haystack = [0x48, 0x8D, 0x01, 0x48, 0x8D, 0x02, 0x48, 0x8D, 0x02, 0x90];
+
+        assert_eq!(
+            Pattern::unique_at(&haystack, 3, 0..0, 16).map(|p| p.to_string()),
+            Ok("48 8D 02 48".to_owned())
+        );
+        // The wildcards do not tell the matches apart.
+        assert_eq!(
+            Pattern::unique_at(&haystack, 6, 2..3, 16).map(|p| p.to_string()),
+            Ok("48 8D ?? 90".to_owned())
+        );
+        assert_eq!(
+            Pattern::unique_at(&haystack, 3, 2..3, 3),
+            Err(ScanError::NotUnique(3))
+        );
+        assert_eq!(
+            Pattern::unique_at(&haystack, 3, 0..0, 16)
+                .unwrap()
+                .find_unique(&haystack),
+            Ok(3)
+        );
+    }
+
+    #[test]
+    fn matches_only_full_windows() {
+        let pattern = pattern("48 ?? 0D");
+
+        assert!(pattern.matches(&[0x48, 0x00, 0x0D, 0xFF]));
+        assert!(!pattern.matches(&[0x48, 0x00]));
+        assert!(!pattern.matches(&[0x49, 0x00, 0x0D]));
+    }
+}
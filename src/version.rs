@@ -9,26 +9,10 @@ use windows::{
     },
 };
 
-pub fn verify() -> bool {
-    get_file_version().is_ok_and(|v| {
-        v == Version {
-            major: 1,
-            minor: 0,
-            revision: 3,
-            build: 0,
-        }
-    })
-}
-
-#[derive(Debug, PartialEq)]
-struct Version {
-    major: u16,
-    minor: u16,
-    revision: u16,
-    build: u16,
-}
+use crate::builds::Version;
 
-fn get_file_version() -> WindowsResult<Version> {
+/// Reads the file version of the main module of the current process.
+pub fn get_file_version() -> WindowsResult<Version> {
     // Resource: VS_VERSION, resource type: RT_VERSION.
     let resource_handle =
         unsafe { FindResourceW(None, PCWSTR::from_raw(1 as _), PCWSTR::from_raw(16 as _)) };